@@ -0,0 +1,254 @@
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+use polyquine::{MapOrder, Quine, QuineContext};
+use std::collections::HashMap;
+use std::hint::black_box;
+
+/// The `Ast` shape from the tests in `src/quine.rs`.
+mod ast {
+    use polyquine::Quine;
+
+    #[derive(Quine)]
+    pub struct Metadata {
+        pub src: String,
+    }
+
+    #[derive(Quine)]
+    pub enum Ast {
+        Num(Box<Metadata>, isize),
+        Mul(Box<Metadata>, Box<Ast>, Box<Ast>),
+        Sum(Box<Metadata>, Vec<Ast>),
+    }
+
+    /// A `Sum` of `width` balanced `Mul` trees of the given `depth`.
+    pub fn build(width: usize, depth: usize) -> Ast {
+        fn mul_tree(depth: usize, n: &mut isize) -> Ast {
+            *n += 1;
+            let meta = Box::new(Metadata { src: n.to_string() });
+            if depth == 0 {
+                Ast::Num(meta, *n)
+            } else {
+                Ast::Mul(
+                    meta,
+                    Box::new(mul_tree(depth - 1, n)),
+                    Box::new(mul_tree(depth - 1, n)),
+                )
+            }
+        }
+
+        let mut n = 0;
+        let terms = (0..width).map(|_| mul_tree(depth, &mut n)).collect();
+        Ast::Sum(
+            Box::new(Metadata {
+                src: String::from("sum"),
+            }),
+            terms,
+        )
+    }
+}
+
+/// The same shape, with `Quine` implemented the way `#[derive(Quine)]` used to:
+/// the type's path is parsed from a string once per value,
+/// and every value builds its own `TokenStream` that is then copied into its parent's.
+mod legacy {
+    use polyquine::{PathSegment, Quine, QuineContext, QuineError};
+    use proc_macro2::TokenStream;
+    use std::collections::HashMap;
+
+    pub struct Metadata {
+        pub src: String,
+    }
+
+    pub enum Ast {
+        Num(Box<Metadata>, isize),
+        Mul(Box<Metadata>, Box<Ast>, Box<Ast>),
+        Sum(Box<Metadata>, Vec<Ast>),
+    }
+
+    fn field(idx: usize, variant: &'static str) -> impl Fn(QuineError) -> QuineError {
+        move |err| {
+            err.with_segment(PathSegment::TupleField(idx))
+                .with_segment(PathSegment::Variant { ty: "Ast", variant })
+        }
+    }
+
+    impl Quine for Metadata {
+        fn try_to_ctor_tokens_with(
+            &self,
+            ctx: &QuineContext,
+            out: &mut TokenStream,
+        ) -> Result<(), QuineError> {
+            out.extend(self.ctor(ctx)?);
+            Ok(())
+        }
+    }
+
+    impl Metadata {
+        fn ctor(&self, ctx: &QuineContext) -> Result<TokenStream, QuineError> {
+            let src = self.src.try_ctor_tokens_with(ctx)?;
+            let path: syn::Path =
+                syn::parse_str(concat!(module_path!(), "::", "Metadata")).unwrap();
+            Ok(quote::quote! {#path { src: #src }})
+        }
+    }
+
+    impl Quine for Ast {
+        fn try_to_ctor_tokens_with(
+            &self,
+            ctx: &QuineContext,
+            out: &mut TokenStream,
+        ) -> Result<(), QuineError> {
+            out.extend(self.ctor(ctx)?);
+            Ok(())
+        }
+    }
+
+    impl Ast {
+        fn ctor(&self, ctx: &QuineContext) -> Result<TokenStream, QuineError> {
+            match self {
+                Ast::Num(meta, n) => {
+                    let meta = meta.try_ctor_tokens_with(ctx).map_err(field(0, "Num"))?;
+                    let n = n.try_ctor_tokens_with(ctx).map_err(field(1, "Num"))?;
+                    let path: syn::Path =
+                        syn::parse_str(concat!(module_path!(), "::", "Ast")).unwrap();
+                    Ok(quote::quote! {#path::Num(#meta, #n)})
+                }
+                Ast::Mul(meta, l, r) => {
+                    let meta = meta.try_ctor_tokens_with(ctx).map_err(field(0, "Mul"))?;
+                    let l = l.try_ctor_tokens_with(ctx).map_err(field(1, "Mul"))?;
+                    let r = r.try_ctor_tokens_with(ctx).map_err(field(2, "Mul"))?;
+                    let path: syn::Path =
+                        syn::parse_str(concat!(module_path!(), "::", "Ast")).unwrap();
+                    Ok(quote::quote! {#path::Mul(#meta, #l, #r)})
+                }
+                Ast::Sum(meta, terms) => {
+                    let meta = meta.try_ctor_tokens_with(ctx).map_err(field(0, "Sum"))?;
+                    let terms = terms.try_ctor_tokens_with(ctx).map_err(field(1, "Sum"))?;
+                    let path: syn::Path =
+                        syn::parse_str(concat!(module_path!(), "::", "Ast")).unwrap();
+                    Ok(quote::quote! {#path::Sum(#meta, #terms)})
+                }
+            }
+        }
+    }
+
+    pub fn build(width: usize, depth: usize) -> Ast {
+        fn mul_tree(depth: usize, n: &mut isize) -> Ast {
+            *n += 1;
+            let meta = Box::new(Metadata { src: n.to_string() });
+            if depth == 0 {
+                Ast::Num(meta, *n)
+            } else {
+                Ast::Mul(
+                    meta,
+                    Box::new(mul_tree(depth - 1, n)),
+                    Box::new(mul_tree(depth - 1, n)),
+                )
+            }
+        }
+
+        let mut n = 0;
+        let terms = (0..width).map(|_| mul_tree(depth, &mut n)).collect();
+        Ast::Sum(
+            Box::new(Metadata {
+                src: String::from("sum"),
+            }),
+            terms,
+        )
+    }
+
+    /// A `Vec` emitted the way the built-in implementation used to:
+    /// the tokens of each element are collected before being quoted into the result.
+    pub struct CollectedVec<T>(pub Vec<T>);
+
+    impl<T: Quine> Quine for CollectedVec<T> {
+        fn try_to_ctor_tokens_with(
+            &self,
+            ctx: &QuineContext,
+            out: &mut TokenStream,
+        ) -> Result<(), QuineError> {
+            let elements = self
+                .0
+                .iter()
+                .map(|item| item.try_ctor_tokens_with(ctx))
+                .collect::<Result<Vec<_>, _>>()?;
+            out.extend(quote::quote! {::std::vec::Vec::from([#(#elements),*])});
+            Ok(())
+        }
+    }
+
+    /// A `HashMap` emitted the way the built-in implementation used to.
+    pub struct CollectedMap<K, V>(pub HashMap<K, V>);
+
+    impl<K: Quine, V: Quine> Quine for CollectedMap<K, V> {
+        fn try_to_ctor_tokens_with(
+            &self,
+            ctx: &QuineContext,
+            out: &mut TokenStream,
+        ) -> Result<(), QuineError> {
+            let entries = self
+                .0
+                .iter()
+                .map(|entry| entry.try_ctor_tokens_with(ctx))
+                .collect::<Result<Vec<_>, _>>()?;
+            out.extend(quote::quote! {::std::collections::HashMap::from([#(#entries),*])});
+            Ok(())
+        }
+    }
+}
+
+fn deep_ast(c: &mut Criterion) {
+    let mut group = c.benchmark_group("deep_ast");
+    group.sample_size(20);
+    for depth in [6, 10] {
+        let nodes = 8 * ((1 << (depth + 1)) - 1);
+        let derived = ast::build(8, depth);
+        group.bench_with_input(BenchmarkId::new("derived", nodes), &derived, |b, ast| {
+            b.iter(|| black_box(ast.ctor_tokens()))
+        });
+        let legacy = legacy::build(8, depth);
+        group.bench_with_input(BenchmarkId::new("parse_str", nodes), &legacy, |b, ast| {
+            b.iter(|| black_box(ast.ctor_tokens()))
+        });
+    }
+    group.finish();
+}
+
+fn large_vec(c: &mut Criterion) {
+    let mut group = c.benchmark_group("large_vec");
+    group.sample_size(20);
+    for len in [1_000, 100_000] {
+        let values: Vec<(u32, String)> = (0..len).map(|i| (i, i.to_string())).collect();
+        group.bench_with_input(BenchmarkId::new("append", len), &values, |b, values| {
+            b.iter(|| black_box(values.ctor_tokens()))
+        });
+        let collected = legacy::CollectedVec(values);
+        group.bench_with_input(BenchmarkId::new("collect", len), &collected, |b, values| {
+            b.iter(|| black_box(values.ctor_tokens()))
+        });
+    }
+    group.finish();
+}
+
+fn large_hashmap(c: &mut Criterion) {
+    let mut group = c.benchmark_group("large_hashmap");
+    group.sample_size(20);
+    for len in [1_000, 100_000] {
+        let map: HashMap<u32, Vec<i64>> = (0..len).map(|i| (i, vec![i64::from(i); 4])).collect();
+        // The legacy implementation emits entries in iteration order
+        let iteration = QuineContext::new().with_map_order(MapOrder::Iteration);
+        group.bench_with_input(BenchmarkId::new("append", len), &map, |b, map| {
+            b.iter(|| black_box(map.ctor_tokens_with(&iteration)))
+        });
+        group.bench_with_input(BenchmarkId::new("sorted", len), &map, |b, map| {
+            b.iter(|| black_box(map.ctor_tokens()))
+        });
+        let collected = legacy::CollectedMap(map);
+        group.bench_with_input(BenchmarkId::new("collect", len), &collected, |b, map| {
+            b.iter(|| black_box(map.ctor_tokens_with(&iteration)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, deep_ast, large_vec, large_hashmap);
+criterion_main!(benches);
@@ -1,131 +1,1129 @@
 use proc_macro::TokenStream;
-use proc_macro2::{Punct, Spacing, TokenStream as TokenStream2};
-use quote::{TokenStreamExt, quote, ToTokens};
-use syn::parse::ParseStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{ToTokens, quote};
+use syn::ext::IdentExt;
+use syn::parse::{Parse, ParseStream};
+use syn::punctuated::Punctuated;
 use syn::{
     Data, DeriveInput, Fields, Generics, Ident, Index, Path, WhereClause, WherePredicate,
     spanned::Spanned,
 };
 
-fn hash_ident(ident: &Ident) -> TokenStream2 {
-    let mut hash_field = TokenStream2::new();
-    hash_field.append(Punct::new('#', Spacing::Joint));
-    hash_field.append(ident.clone());
-    hash_field
+/// Returns the items declaring and caching the type's path, to be placed once at the top of the generated function,
+/// and an expression evaluating to the resolved path, which is cheap to evaluate once per value.
+fn build_path_expr(
+    krate: &Path,
+    ident: &Ident,
+    module_prefix: Option<&Path>,
+    crate_path: &TokenStream2,
+) -> (TokenStream2, TokenStream2) {
+    let prefix = match module_prefix {
+        Some(prefix) => {
+            quote! { ::core::option::Option::Some(|| #krate::__private::quote::quote!(#prefix)) }
+        }
+        None => quote! { ::core::option::Option::None },
+    };
+    let items = quote! {
+        #[allow(dead_code)]
+        const POLYQUINE_TYPE_PATH: #krate::__private::TypePathDecl = #krate::__private::TypePathDecl {
+            ident: stringify!(#ident),
+            module_path: module_path!(),
+            prefix: #prefix,
+            krate: #crate_path,
+        };
+        ::std::thread_local! {
+            #[allow(dead_code)]
+            static POLYQUINE_TYPE_PATH_CACHE: #krate::__private::TypePathCache =
+                const { #krate::__private::TypePathCache::new() };
+        }
+    };
+    let expr = quote! {
+        POLYQUINE_TYPE_PATH_CACHE.with(|cache| cache.get(ctx, &POLYQUINE_TYPE_PATH))?
+    };
+    (items, expr)
 }
 
-fn build_path_setup(ident: &Ident, module_prefix: Option<&Path>) -> TokenStream2 {
-    match module_prefix {
-        Some(prefix) => {
-            quote! {
-                let path: ::syn::Path = ::syn::parse_quote!(#prefix::#ident);
+/// The `#[polyquine(..)]` attributes of the type.
+struct ContainerAttrs {
+    /// `crate = path`: the path to the `polyquine` crate, for crates that re-export it under another name
+    krate: Path,
+    /// `prefix = path`: the path the type is emitted under, instead of its module path
+    prefix: Option<Path>,
+    /// `path(crate)`, `path($crate)` or `path(alias = name)`: how the type's crate is named in its path
+    crate_path: Option<CratePathAttr>,
+    /// `ctor = path, args(a, b)`: a constructor called with the given fields, or all of them
+    ctor: Option<(Path, Option<Vec<syn::Member>>)>,
+    /// `via = Proxy`: a type that implements `Quine` and `From<&Self>`, which `Self` is converted from
+    via: Option<syn::Type>,
+    /// `hidden_ctor`: generate a public `__polyquine_new` taking every field, and call it
+    hidden_ctor: bool,
+    /// `with = path::to::fn`: a function returning the constructor tokens of the whole value
+    with: Option<Path>,
+    /// `unsafe_active = field`: the field of a union that the user asserts is initialized in every value
+    active: Option<Ident>,
+    /// `bound = ".."`: the predicates of the `Quine` impl, instead of the bounds inferred from the fields
+    bound: Option<Vec<WherePredicate>>,
+    /// `const`: emit the fields in const form, e.g. `String`s as `&'static str` literals
+    konst: bool,
+}
+
+/// Parse the `#[polyquine(..)]` container attributes.
+fn parse_container_attrs(attrs: &[syn::Attribute]) -> syn::Result<ContainerAttrs> {
+    let mut krate: Option<Path> = None;
+    let mut prefix: Option<Path> = None;
+    let mut crate_path: Option<CratePathAttr> = None;
+    let mut ctor: Option<Path> = None;
+    let mut args: Option<(proc_macro2::Span, Vec<syn::Member>)> = None;
+    let mut via: Option<syn::Type> = None;
+    let mut hidden_ctor: Option<proc_macro2::Span> = None;
+    let mut with: Option<Path> = None;
+    let mut active: Option<Ident> = None;
+    let mut bound: Option<Vec<WherePredicate>> = None;
+    let mut konst: Option<proc_macro2::Span> = None;
+    // The keys that choose how the value is built, which exclude each other
+    let mut mode: Option<String> = None;
+    for attr in attrs {
+        if attr.path().is_ident("polyquine") {
+            attr.parse_nested_meta(|meta| {
+                let key = meta.path.get_ident().map(Ident::to_string).unwrap_or_default();
+                let duplicate = match key.as_str() {
+                    "crate" => krate.is_some(),
+                    "prefix" => prefix.is_some(),
+                    "path" => crate_path.is_some(),
+                    "ctor" => ctor.is_some(),
+                    "args" => args.is_some(),
+                    "via" => via.is_some(),
+                    "hidden_ctor" => hidden_ctor.is_some(),
+                    "with" => with.is_some(),
+                    "unsafe_active" => active.is_some(),
+                    "bound" => bound.is_some(),
+                    "const" => konst.is_some(),
+                    "skip" | "default" | "expr" => {
+                        return Err(meta.error(format!("`{key}` cannot be used on a type")));
+                    }
+                    "active" => {
+                        return Err(meta.error(
+                            "reading a union field is `unsafe`, use `unsafe_active = field` and uphold its safety contract",
+                        ));
+                    }
+                    _ => {
+                        return Err(meta.error(
+                            "unknown polyquine attribute, expected `crate`, `prefix`, `path`, `ctor`, `args`, `via`, `hidden_ctor`, `with`, `unsafe_active`, `bound` or `const`",
+                        ));
+                    }
+                };
+                if duplicate {
+                    return Err(meta.error(format!("duplicate polyquine({key}) attribute")));
+                }
+                let span = meta.path.span();
+                match key.as_str() {
+                    "crate" => {
+                        let value = meta.value()?;
+                        krate = Some(if value.peek(syn::LitStr) {
+                            value.parse::<syn::LitStr>()?.parse()?
+                        } else {
+                            value.parse()?
+                        });
+                    }
+                    "prefix" => prefix = Some(meta.value()?.parse()?),
+                    "path" => {
+                        let content;
+                        syn::parenthesized!(content in meta.input);
+                        crate_path = Some(parse_crate_path(&content)?);
+                    }
+                    "bound" => bound = Some(parse_bound(meta.value()?)?),
+                    "args" => {
+                        let content;
+                        syn::parenthesized!(content in meta.input);
+                        let members = content.parse_terminated(syn::Member::parse, syn::Token![,])?;
+                        args = Some((span, members.into_iter().collect()));
+                    }
+                    "ctor" => ctor = Some(meta.value()?.parse()?),
+                    "via" => via = Some(meta.value()?.parse()?),
+                    "hidden_ctor" => hidden_ctor = Some(span),
+                    "with" => with = Some(meta.value()?.parse()?),
+                    "const" => konst = Some(span),
+                    _ => active = Some(meta.value()?.parse()?),
+                }
+                if !matches!(key.as_str(), "crate" | "prefix" | "path" | "args" | "bound" | "const") {
+                    if let Some(other) = &mode {
+                        return Err(syn::Error::new(span, format!("`{key}` cannot be used together with `{other}`")));
+                    }
+                    mode = Some(key);
+                }
+                Ok(())
+            })?;
+        }
+    }
+
+    // The other modes call functions, which may not be `const`; a union's active field is set by a literal
+    if let (Some(span), Some(other)) = (konst, &mode)
+        && other != "unsafe_active"
+    {
+        return Err(syn::Error::new(
+            span,
+            format!("`const` cannot be used together with `{other}`"),
+        ));
+    }
+
+    let ctor = match (ctor, args) {
+        (Some(ctor), args) => Some((ctor, args.map(|(_, args)| args))),
+        (None, Some((span, _))) => {
+            return Err(syn::Error::new(span, "`args(..)` requires `ctor = ..`"));
+        }
+        (None, None) => None,
+    };
+    Ok(ContainerAttrs {
+        krate: krate.unwrap_or_else(|| syn::parse_quote!(::polyquine)),
+        prefix,
+        crate_path,
+        ctor,
+        via,
+        hidden_ctor: hidden_ctor.is_some(),
+        with,
+        active,
+        bound,
+        konst: konst.is_some(),
+    })
+}
+
+/// How the type's crate is named at the start of its path, a `polyquine::CratePath` other than `Name`.
+enum CratePathAttr {
+    Crate,
+    DollarCrate,
+    Alias(String),
+}
+
+impl CratePathAttr {
+    /// An expression constructing the corresponding `polyquine::CratePath`.
+    fn to_expr(&self, krate: &Path) -> TokenStream2 {
+        match self {
+            CratePathAttr::Crate => quote! { #krate::CratePath::Crate },
+            CratePathAttr::DollarCrate => quote! { #krate::CratePath::DollarCrate },
+            CratePathAttr::Alias(alias) => {
+                quote! { #krate::CratePath::Alias(::std::borrow::Cow::Borrowed(#alias)) }
             }
         }
-        None => {
-            quote! {
-                let fully_qualified_path = concat!(module_path!(), "::", stringify!(#ident));
-                let path: ::syn::Path = ::syn::parse_str(fully_qualified_path).unwrap();
+    }
+}
+
+/// Parse the `crate`, `$crate` or `alias = name` of `#[polyquine(path(..))]` or the deprecated `#[polyquine_path(..)]`.
+fn parse_crate_path(parser: ParseStream) -> syn::Result<CratePathAttr> {
+    if parser.peek(syn::Token![crate]) {
+        let _: syn::Token![crate] = parser.parse()?;
+        return Ok(CratePathAttr::Crate);
+    }
+    if parser.peek(syn::Token![$]) {
+        let _: syn::Token![$] = parser.parse()?;
+        let _: syn::Token![crate] = parser.parse()?;
+        return Ok(CratePathAttr::DollarCrate);
+    }
+
+    let ident: syn::Ident = parser.parse()?;
+    if ident != "alias" {
+        return Err(syn::Error::new(
+            ident.span(),
+            "expected `crate`, `$crate` or `alias = name`",
+        ));
+    }
+    let _: syn::Token![=] = parser.parse()?;
+    let alias = parser.call(Ident::parse_any)?.unraw().to_string();
+    Ok(CratePathAttr::Alias(alias))
+}
+
+/// How a field is emitted, according to its `#[polyquine(..)]` attributes.
+enum FieldMode {
+    /// Its own constructor tokens
+    Quine,
+    /// `#[polyquine(skip)]`, with the expression given by `default = expr` or `Default::default()`
+    Skip(Option<TokenStream2>),
+    /// `#[polyquine(with = path)]`: the tokens returned by `path(&field)`
+    With(Path),
+    /// `#[polyquine(expr = "..")]`: a fixed expression
+    Expr(TokenStream2),
+}
+
+/// A field to emit: its name (for named fields), an expression referring to it, its `PathSegment` and how to emit it.
+struct EmitField {
+    name: Option<String>,
+    expr: TokenStream2,
+    segment: TokenStream2,
+    mode: FieldMode,
+}
+
+/// Parse everything up to the next top-level comma as an expression.
+/// Commas inside generic arguments must be wrapped in parentheses or braces.
+fn parse_expr_tokens(input: ParseStream) -> syn::Result<TokenStream2> {
+    let mut expr = TokenStream2::new();
+    while !input.is_empty() && !input.peek(syn::Token![,]) {
+        expr.extend(TokenStream2::from(input.parse::<proc_macro2::TokenTree>()?));
+    }
+    if expr.is_empty() {
+        return Err(input.error("expected an expression"));
+    }
+    Ok(expr)
+}
+
+/// Parse how a field is emitted, from [`parse_field_attrs`].
+fn parse_field_mode(field: &syn::Field) -> syn::Result<FieldMode> {
+    Ok(parse_field_attrs(field)?.0)
+}
+
+/// Parse the `#[polyquine(..)]` attributes of a field:
+/// `skip`, optionally with `default = expr`, `with = path::to::fn` or `expr = ".."`,
+/// and `bound = ".."`, the predicates that replace the bounds inferred from its type.
+fn parse_field_attrs(field: &syn::Field) -> syn::Result<(FieldMode, Option<Vec<WherePredicate>>)> {
+    let mut bound = None;
+    let mut skip = None;
+    let mut default = None;
+    let mut with = None;
+    let mut expr = None;
+    for attr in &field.attrs {
+        if !attr.path().is_ident("polyquine") {
+            continue;
+        }
+        attr.parse_nested_meta(|meta| {
+            let duplicate = if meta.path.is_ident("skip") {
+                skip.replace(meta.path.span()).is_some()
+            } else if meta.path.is_ident("default") {
+                default.replace((meta.path.span(), parse_expr_tokens(meta.value()?)?)).is_some()
+            } else if meta.path.is_ident("with") {
+                with.replace(meta.value()?.parse::<Path>()?).is_some()
+            } else if meta.path.is_ident("expr") {
+                let lit = meta.value()?.parse::<syn::LitStr>()?;
+                expr.replace((lit.span(), lit.parse::<TokenStream2>()?)).is_some()
+            } else if meta.path.is_ident("bound") {
+                bound.replace(parse_bound(meta.value()?)?).is_some()
+            } else if let Some(key) = TYPE_KEYS.iter().find(|key| meta.path.is_ident(key)) {
+                return Err(meta.error(format!("`{key}` cannot be used on a field")));
+            } else {
+                return Err(meta.error("unknown polyquine field attribute, expected `skip`, `default`, `with`, `expr` or `bound`"));
+            };
+            if duplicate {
+                return Err(meta.error("duplicate polyquine field attribute"));
             }
+            Ok(())
+        })?;
+    }
+
+    let mode = match (skip, default, with, expr) {
+        (None, None, None, None) => FieldMode::Quine,
+        (Some(_), default, None, None) => FieldMode::Skip(default.map(|(_, tokens)| tokens)),
+        (None, Some((span, _)), _, _) => {
+            return Err(syn::Error::new(span, "`default` requires `skip`"));
+        }
+        (None, None, Some(path), None) => FieldMode::With(path),
+        (None, None, None, Some((_, tokens))) => FieldMode::Expr(tokens),
+        (_, _, Some(path), _) => {
+            return Err(syn::Error::new(
+                path.span(),
+                "only one of `skip`, `with` and `expr` can be used on a field",
+            ));
+        }
+        (_, _, _, Some((span, _))) => {
+            return Err(syn::Error::new(
+                span,
+                "only one of `skip`, `with` and `expr` can be used on a field",
+            ));
+        }
+    };
+    Ok((mode, bound))
+}
+
+/// Parse the value of `bound = "T: Quine, U: Clone"`: where predicates separated by commas.
+fn parse_bound(input: ParseStream) -> syn::Result<Vec<WherePredicate>> {
+    let lit = input.parse::<syn::LitStr>()?;
+    let predicates =
+        lit.parse_with(Punctuated::<WherePredicate, syn::Token![,]>::parse_terminated)?;
+    Ok(predicates.into_iter().collect())
+}
+
+/// Builds statements emitting a group delimited by `delimiter` (a `proc_macro2::Delimiter` variant)
+/// that contains the constructor tokens of each field, separated by commas.
+/// With `konst`, the fields are emitted in const form, and the expressions given for them are trusted to be `const`;
+/// otherwise in the usual form, even within a `const` type.
+fn emit_fields(
+    krate: &Path,
+    delimiter: &str,
+    konst: bool,
+    fields: impl IntoIterator<Item = EmitField>,
+) -> TokenStream2 {
+    let delimiter = Ident::new(delimiter, proc_macro2::Span::call_site());
+    let mut stmts = quote! {
+        emitter.open(#krate::__private::proc_macro2::Delimiter::#delimiter);
+        emitter.enter_const(#konst);
+    };
+    // Expressions that may not be `const`
+    let non_const =
+        (!konst).then(|| quote! { emitter.non_const(::core::any::type_name::<Self>())?; });
+    for (
+        i,
+        EmitField {
+            name,
+            expr,
+            segment,
+            mode,
+        },
+    ) in fields.into_iter().enumerate()
+    {
+        if i > 0 {
+            stmts.extend(quote! { emitter.append(#krate::__private::append_comma); });
+        }
+        if let Some(name) = name {
+            stmts.extend(
+                quote! { emitter.append(|out| #krate::__private::append_field(out, #name)); },
+            );
         }
+        stmts.extend(match mode {
+            FieldMode::Quine => quote! { emitter.value_at(#segment, #expr); },
+            // Rejected for `const` types, see `check_const_fields`
+            FieldMode::Skip(None) => quote! {
+                #non_const
+                emitter.append(|out| out.extend(#krate::__private::quote::quote!(::core::default::Default::default())));
+            },
+            FieldMode::Skip(Some(tokens)) | FieldMode::Expr(tokens) => quote! {
+                #non_const
+                emitter.append(|out| out.extend(#krate::__private::quote::quote!(#tokens)));
+            },
+            FieldMode::With(path) => quote! {
+                #non_const
+                emitter.append(|out| out.extend(#path(#expr)));
+            },
+        });
     }
+    stmts.extend(quote! {
+        emitter.leave_const();
+        emitter.close();
+    });
+    stmts
 }
 
-/// When deriving for types with generics, we add an extra bound `T: ... + Quine`.
+/// The fields of the struct or union, or of every variant of the enum.
+fn all_fields(data: &Data) -> Vec<&syn::Field> {
+    match data {
+        Data::Struct(data) => data.fields.iter().collect(),
+        Data::Enum(data) => data
+            .variants
+            .iter()
+            .flat_map(|variant| &variant.fields)
+            .collect(),
+        Data::Union(data) => data.fields.named.iter().collect(),
+    }
+}
+
+/// Parses the `#[polyquine(..)]` attributes of every field, including those that are not emitted,
+/// and rejects them where they have no effect: on the fields of a type emitted `with` a function or `via` another type,
+/// described by `replaced`, and on the fields of a union other than the `unsafe_active` one.
+fn check_field_attrs(
+    data: &Data,
+    replaced: Option<&str>,
+    active: Option<&Ident>,
+) -> syn::Result<()> {
+    for field in all_fields(data) {
+        parse_field_attrs(field)?;
+        let Some(attr) = field
+            .attrs
+            .iter()
+            .find(|attr| attr.path().is_ident("polyquine"))
+        else {
+            continue;
+        };
+        let message = match (replaced, data) {
+            (Some(how), _) => format!("field attributes have no effect on a type emitted {how}"),
+            (None, Data::Union(_)) if field.ident.as_ref() != active => {
+                "field attributes only apply to the `unsafe_active` field of a union".to_string()
+            }
+            _ => continue,
+        };
+        return Err(syn::Error::new_spanned(attr, message));
+    }
+    Ok(())
+}
+
+/// Rejects the skipped fields of a `const` type without a `default = ..`, since `Default::default()` is not `const`.
+fn check_const_fields(data: &Data) -> syn::Result<()> {
+    for field in all_fields(data) {
+        if let FieldMode::Skip(None) = parse_field_mode(field)? {
+            return Err(syn::Error::new(
+                field
+                    .ident
+                    .as_ref()
+                    .map_or_else(|| field.ty.span(), Ident::span),
+                "a skipped field of a `const` type needs `default = ..`, `Default::default()` is not `const`",
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Picks the fields passed to a `ctor`, in the order of `args(..)`, or all of them in declaration order.
+/// The fields are emitted as positional arguments.
+fn select_args(
+    fields: Vec<(syn::Member, EmitField)>,
+    args: Option<&[syn::Member]>,
+) -> syn::Result<Vec<EmitField>> {
+    let Some(args) = args else {
+        return Ok(fields
+            .into_iter()
+            .map(|(_, field)| EmitField {
+                name: None,
+                ..field
+            })
+            .collect());
+    };
+    let mut fields = fields.into_iter().map(Some).collect::<Vec<_>>();
+    args.iter()
+        .map(|arg| {
+            let position = fields
+                .iter()
+                .position(|field| matches!(field, Some((member, _)) if member == arg));
+            match position.and_then(|i| fields[i].take()) {
+                Some((_, field)) => Ok(EmitField {
+                    name: None,
+                    ..field
+                }),
+                None if position.is_some() => Err(syn::Error::new(
+                    arg.span(),
+                    "field is passed to `ctor` more than once",
+                )),
+                None => Err(syn::Error::new(arg.span(), "no such field")),
+            }
+        })
+        .collect()
+}
+
+/// Builds `__polyquine_new`, which takes every field of the struct in declaration order.
+/// It is public so that the output of `Quine` compiles outside of the struct's module, even when its fields are private.
+fn build_hidden_ctor(ident: &Ident, generics: &Generics, fields: &Fields) -> TokenStream2 {
+    let (impl_gen, ty_gen, where_clause) = generics.split_for_impl();
+    let (params, init) = match fields {
+        Fields::Unit => (Vec::new(), quote! { #ident }),
+        Fields::Unnamed(fields) => {
+            let (params, names): (Vec<_>, Vec<_>) = fields
+                .unnamed
+                .iter()
+                .enumerate()
+                .map(|(i, f)| {
+                    let name = Ident::new(&format!("field_{i}"), f.span());
+                    let ty = &f.ty;
+                    (quote! { #name: #ty }, name)
+                })
+                .unzip();
+            (params, quote! { #ident(#(#names),*) })
+        }
+        Fields::Named(fields) => {
+            let (params, names): (Vec<_>, Vec<_>) = fields
+                .named
+                .iter()
+                .map(|f| {
+                    let name = f.ident.as_ref().expect("named fields have an ident");
+                    let ty = &f.ty;
+                    (quote! { #name: #ty }, name)
+                })
+                .unzip();
+            (params, quote! { #ident { #(#names),* } })
+        }
+    };
+    quote! {
+        impl #impl_gen #ident #ty_gen #where_clause {
+            #[doc(hidden)]
+            #[allow(clippy::too_many_arguments)]
+            pub fn __polyquine_new(#(#params),*) -> Self {
+                #init
+            }
+        }
+    }
+}
+
+/// Builds the expression emitting the path of a `ctor`.
+/// Paths starting with `Self` or the name of the type are resolved like the type's own path, type arguments included,
+/// any other path is emitted as written.
+fn ctor_path_expr(
+    krate: &Path,
+    ident: &Ident,
+    ctor: &Path,
+    path_expr: &TokenStream2,
+) -> TokenStream2 {
+    let mut segments = ctor.segments.iter();
+    match segments.next() {
+        Some(first)
+            if ctor.leading_colon.is_none()
+                && first.arguments.is_none()
+                && (first.ident == "Self" || first.ident == *ident) =>
+        {
+            let rest = segments.collect::<Vec<_>>();
+            quote! {{
+                let mut path = #path_expr;
+                path.extend(#krate::__private::quote::quote!(#(:: #rest)*));
+                path
+            }}
+        }
+        _ => quote! { #krate::__private::quote::quote!(#ctor) },
+    }
+}
+
+fn named_field_segment(krate: &Path, ident: &Ident) -> TokenStream2 {
+    let name = ident.unraw().to_string();
+    quote! { #krate::PathSegment::Field(#name) }
+}
+
+fn tuple_field_segment(krate: &Path, idx: usize) -> TokenStream2 {
+    quote! { #krate::PathSegment::TupleField(#idx) }
+}
+
+/// Infers the bounds of the `Quine` impl from the types of the fields that are emitted, the way serde does.
 /// For example, given:
 /// ```ignore
-/// struct MyStruct<T: SomeTrait> {
-///     field: T,
+/// struct MyStruct<T: Trait, U, V> {
+///     values: Vec<T>,
+///     assoc: T::Assoc,
+///     marker: PhantomData<U>,
+///     #[polyquine(skip)]
+///     skipped: V,
 /// }
 /// ```
 /// We want to generate:
 /// ```ignore
-/// impl<T: SomeTrait + Quine> Quine for MyStruct<T> {
+/// impl<T: Trait, U, V> Quine for MyStruct<T, U, V> where T: Quine, T::Assoc: Quine {
 ///     ...
 /// }
 /// ```
-/// To do that, we either modify the existing where clause or create a new one.
-fn build_where_clause(generics: &Generics) -> Option<WhereClause> {
-    let gen_params = generics.params.iter().collect::<Vec<_>>();
-    if gen_params.is_empty() {
-        return None;
-    }
-    match &generics.where_clause {
-        Some(a_wc) => {
-            let mut wc = a_wc.clone();
-            for p in &mut wc.predicates {
-                if let WherePredicate::Type(pt) = p {
-                    let tb: syn::TraitBound = syn::parse_str("Quine").unwrap();
-                    (&mut pt.bounds).push(syn::TypeParamBound::Trait(tb));
+/// A field's `#[polyquine(bound = "..")]` replaces the bounds inferred from its type.
+fn infer_bounds<'f>(
+    krate: &Path,
+    generics: &Generics,
+    fields: impl IntoIterator<Item = &'f syn::Field>,
+) -> syn::Result<Vec<WherePredicate>> {
+    let params: Vec<&Ident> = generics.type_params().map(|param| &param.ident).collect();
+    let mut predicates = Vec::new();
+    let mut bounded: Vec<syn::Type> = Vec::new();
+    for field in fields {
+        match parse_field_attrs(field)? {
+            (_, Some(bound)) => predicates.extend(bound),
+            (FieldMode::Quine, None) => collect_bounded(&field.ty, &params, &mut bounded),
+            _ => {}
+        }
+    }
+    let mut seen = std::collections::HashSet::new();
+    for ty in bounded {
+        if seen.insert(ty.to_token_stream().to_string()) {
+            predicates.push(syn::parse_quote!(#ty: #krate::Quine));
+        }
+    }
+    Ok(predicates)
+}
+
+/// The fields whose types need bounds: the arguments of a `ctor`, the `unsafe_active` field of a union,
+/// or the fields of the struct or of the enum's variants that are not skipped or emitted `with` a function.
+/// None for a type emitted `with` a function or `via` another type.
+fn emitted_fields<'d>(
+    data: &'d Data,
+    ctor: Option<&(Path, Option<Vec<syn::Member>>)>,
+    replaced: bool,
+    active: Option<&Ident>,
+) -> syn::Result<Vec<&'d syn::Field>> {
+    if replaced {
+        return Ok(Vec::new());
+    }
+    match data {
+        Data::Struct(data) => {
+            let members = data.fields.members();
+            Ok(match ctor {
+                Some((_, Some(args))) => data
+                    .fields
+                    .iter()
+                    .zip(members)
+                    .filter(|(_, member)| args.contains(member))
+                    .map(|(field, _)| field)
+                    .collect(),
+                _ => data.fields.iter().collect(),
+            })
+        }
+        Data::Enum(data) => {
+            let mut fields = Vec::new();
+            for variant in &data.variants {
+                if let VariantMode::Quine = parse_variant_mode(variant)? {
+                    fields.extend(&variant.fields);
                 }
             }
-            Some(wc)
+            Ok(fields)
         }
-        None => {
-            let mut bounds: Vec<syn::TypeParam> = Vec::new();
-            for p in &gen_params {
-                if let syn::GenericParam::Type(a_tp) = p {
-                    let mut tp = a_tp.clone();
-                    tp.default = None;
-                    let tb = syn::parse_str("Quine").unwrap();
-                    (&mut tp.bounds).push(syn::TypeParamBound::Trait(tb));
-                    bounds.push(tp);
+        Data::Union(data) => Ok(data
+            .fields
+            .named
+            .iter()
+            .filter(|field| field.ident.as_ref() == active)
+            .collect()),
+    }
+}
+
+/// Collects the types within `ty` that need a `Quine` bound: the type parameters in `params` it mentions,
+/// and their associated types, e.g. `T::Assoc` or `<T as Trait>::Assoc`.
+/// The type arguments of `PhantomData`, which implements `Quine` for any type, are left out.
+fn collect_bounded(ty: &syn::Type, params: &[&Ident], out: &mut Vec<syn::Type>) {
+    match ty {
+        syn::Type::Path(path) => {
+            if let Some(qself) = &path.qself {
+                if mentions_param(&qself.ty, params) {
+                    out.push(ty.clone());
                 }
+                return;
+            }
+            let segments = &path.path.segments;
+            // `T`, or an associated type `T::Assoc`, which is bounded rather than `T`
+            if path.path.leading_colon.is_none()
+                && let Some(first) = segments.first()
+                && params.contains(&&first.ident)
+            {
+                out.push(ty.clone());
+                return;
+            }
+            if segments
+                .last()
+                .is_some_and(|last| last.ident == "PhantomData")
+            {
+                return;
+            }
+            for segment in segments {
+                match &segment.arguments {
+                    syn::PathArguments::AngleBracketed(args) => {
+                        for arg in &args.args {
+                            match arg {
+                                syn::GenericArgument::Type(ty) => collect_bounded(ty, params, out),
+                                syn::GenericArgument::AssocType(assoc) => {
+                                    collect_bounded(&assoc.ty, params, out)
+                                }
+                                _ => {}
+                            }
+                        }
+                    }
+                    syn::PathArguments::Parenthesized(args) => {
+                        for input in &args.inputs {
+                            collect_bounded(input, params, out);
+                        }
+                        if let syn::ReturnType::Type(_, output) = &args.output {
+                            collect_bounded(output, params, out);
+                        }
+                    }
+                    syn::PathArguments::None => {}
+                }
+            }
+        }
+        syn::Type::Array(array) => collect_bounded(&array.elem, params, out),
+        syn::Type::Slice(slice) => collect_bounded(&slice.elem, params, out),
+        syn::Type::Reference(reference) => collect_bounded(&reference.elem, params, out),
+        syn::Type::Ptr(ptr) => collect_bounded(&ptr.elem, params, out),
+        syn::Type::Paren(paren) => collect_bounded(&paren.elem, params, out),
+        syn::Type::Group(group) => collect_bounded(&group.elem, params, out),
+        syn::Type::Tuple(tuple) => {
+            for elem in &tuple.elems {
+                collect_bounded(elem, params, out);
             }
-            let where_toks = quote! {
-                where #(#bounds),*
-            };
-            let wc = syn::parse2(where_toks.clone()).expect(format!("Could not parse where_toks: `{}`", where_toks).as_str());
-            Some(wc)
         }
+        // Trait objects, function pointers, macros, ...: the bound would be on the whole type, which is bounded by the field's own impl
+        _ => {}
+    }
+}
+
+/// Whether `ty` mentions one of the type parameters in `params`.
+fn mentions_param(ty: &syn::Type, params: &[&Ident]) -> bool {
+    let mut found = Vec::new();
+    collect_bounded(ty, params, &mut found);
+    !found.is_empty()
+}
+
+/// Appends `predicates` to the where clause of the type.
+fn build_where_clause(generics: &Generics, predicates: Vec<WherePredicate>) -> Option<WhereClause> {
+    if predicates.is_empty() {
+        return generics.where_clause.clone();
     }
+    let mut where_clause = generics
+        .where_clause
+        .clone()
+        .unwrap_or_else(|| WhereClause {
+            where_token: Default::default(),
+            predicates: Punctuated::new(),
+        });
+    where_clause.predicates.extend(predicates);
+    Some(where_clause)
 }
 
-fn parse_custom_arm(variant: &syn::Variant) -> Option<TokenStream2> {
+/// Whether the type parameter `param` has a trait bound matching `pred`, inline or in the where clause.
+fn has_bound(
+    generics: &Generics,
+    param: &syn::TypeParam,
+    pred: impl Fn(&syn::TraitBound) -> bool,
+) -> bool {
+    let matches = |bounds: &Punctuated<syn::TypeParamBound, syn::Token![+]>| {
+        bounds
+            .iter()
+            .any(|bound| matches!(bound, syn::TypeParamBound::Trait(bound) if pred(bound)))
+    };
+    let in_where = generics
+        .where_clause
+        .iter()
+        .flat_map(|clause| &clause.predicates)
+        .any(|predicate| match predicate {
+            WherePredicate::Type(predicate) => match &predicate.bounded_ty {
+                syn::Type::Path(ty) => {
+                    ty.qself.is_none()
+                        && ty.path.is_ident(&param.ident)
+                        && matches(&predicate.bounds)
+                }
+                _ => false,
+            },
+            _ => false,
+        });
+    matches(&param.bounds) || in_where
+}
+
+/// The functions appending the type arguments of the type, e.g. for `Type::<A, B>`:
+/// `<A as Quine>::try_to_type_tokens_with` for the sized type parameters bounded by `Quine`, `append_infer` for the others,
+/// and the value of const parameters. Lifetimes are left out, for the compiler to infer.
+fn type_args(krate: &Path, generics: &Generics) -> Vec<TokenStream2> {
+    generics.params.iter().filter_map(|param| match param {
+        syn::GenericParam::Type(param) => {
+            let quine = has_bound(generics, param, |bound| bound.path.segments.last().is_some_and(|last| last.ident == "Quine"));
+            let unsized_ = has_bound(generics, param, |bound| matches!(bound.modifier, syn::TraitBoundModifier::Maybe(_)));
+            let ident = &param.ident;
+            Some(if quine && !unsized_ {
+                quote! { <#ident as #krate::Quine>::try_to_type_tokens_with }
+            } else {
+                quote! { #krate::__private::append_infer }
+            })
+        }
+        syn::GenericParam::Const(param) => {
+            let ident = &param.ident;
+            Some(quote! {
+                |_: &#krate::QuineContext, out: &mut #krate::__private::proc_macro2::TokenStream| {
+                    #krate::__private::append_group(out, #krate::__private::proc_macro2::Delimiter::Brace, |out| {
+                        #krate::__private::quote::ToTokens::to_tokens(&#ident, out);
+                        ::core::result::Result::Ok(())
+                    })
+                }
+            })
+        }
+        syn::GenericParam::Lifetime(_) => None,
+    }).collect()
+}
+
+/// The keys of `#[polyquine(..)]` on types.
+const TYPE_KEYS: [&str; 11] = [
+    "crate",
+    "prefix",
+    "path",
+    "ctor",
+    "args",
+    "via",
+    "hidden_ctor",
+    "with",
+    "unsafe_active",
+    "bound",
+    "const",
+];
+
+/// How an enum variant is emitted.
+enum VariantMode {
+    /// Field by field
+    Quine,
+    /// As an error: `#[polyquine(skip)]`
+    Skip,
+    /// As the tokens returned by the body of a match arm on the variant: `#[polyquine(with = (pattern) => body)]`
+    Arm(TokenStream2, TokenStream2),
+    /// As the tokens returned by `function(&self)`: `#[polyquine(with = function)]`
+    With(Path),
+}
+
+/// Parse the `#[polyquine(..)]` attributes of an enum variant, `skip` or `with = ..`,
+/// as well as the deprecated `#[polyquine_skip]` and `#[polyquine_with(arm = ..)]`.
+fn parse_variant_mode(variant: &syn::Variant) -> syn::Result<VariantMode> {
+    let mut skip: Option<proc_macro2::Span> = None;
+    let mut with: Option<(proc_macro2::Span, VariantMode)> = None;
     for attr in &variant.attrs {
-        if attr.path().is_ident("polyquine_with") {
-            return attr
-                .parse_args_with(|parser: ParseStream| {
-                    // Parse "arm ="
-                    let ident: syn::Ident = parser.parse()?;
-                    if ident != "arm" {
-                        return Err(parser.error("expected 'arm ='"));
-                    }
+        if attr.path().is_ident("polyquine_skip") {
+            attr.meta.require_path_only()?;
+            if skip.replace(attr.path().span()).is_some() {
+                return Err(syn::Error::new_spanned(
+                    attr,
+                    "duplicate polyquine_skip attribute",
+                ));
+            }
+        } else if attr.path().is_ident("polyquine_with") {
+            let arm = attr.parse_args_with(parse_legacy_arm)?;
+            if with.replace((attr.path().span(), arm)).is_some() {
+                return Err(syn::Error::new_spanned(
+                    attr,
+                    "duplicate polyquine_with attribute",
+                ));
+            }
+        } else if attr.path().is_ident("polyquine") {
+            attr.parse_nested_meta(|meta| {
+                let duplicate = if meta.path.is_ident("skip") {
+                    skip.replace(meta.path.span()).is_some()
+                } else if meta.path.is_ident("with") {
+                    let mode = parse_variant_with(meta.value()?)?;
+                    with.replace((meta.path.span(), mode)).is_some()
+                } else if let Some(key) = TYPE_KEYS
+                    .iter()
+                    .chain(&["default", "expr"])
+                    .find(|key| meta.path.is_ident(key))
+                {
+                    return Err(meta.error(format!("`{key}` cannot be used on an enum variant")));
+                } else {
+                    return Err(meta
+                        .error("unknown polyquine variant attribute, expected `skip` or `with`"));
+                };
+                if duplicate {
+                    return Err(meta.error("duplicate polyquine variant attribute"));
+                }
+                Ok(())
+            })?;
+        }
+    }
+    match (skip, with) {
+        (Some(skip), Some((with, _))) => {
+            let mut err = syn::Error::new(with, "a variant cannot have both `skip` and `with`");
+            err.combine(syn::Error::new(skip, "`skip` is here"));
+            Err(err)
+        }
+        (Some(_), None) => Ok(VariantMode::Skip),
+        (None, Some((_, mode))) => Ok(mode),
+        (None, None) => Ok(VariantMode::Quine),
+    }
+}
 
-                    let _: syn::Token![=] = parser.parse()?;
+/// Parse `(pattern) => body` up to the next top-level comma, or a path to a function if there is no `=>`.
+fn parse_variant_with(input: ParseStream) -> syn::Result<VariantMode> {
+    let mut pattern = TokenStream2::new();
+    while !input.is_empty() && !input.peek(syn::Token![,]) && !input.peek(syn::Token![=>]) {
+        pattern.extend(TokenStream2::from(input.parse::<proc_macro2::TokenTree>()?));
+    }
+    if !input.peek(syn::Token![=>]) {
+        if pattern.is_empty() {
+            return Err(input.error("expected a function or `(pattern) => body`"));
+        }
+        return Ok(VariantMode::With(syn::parse2(pattern)?));
+    }
+    let _: syn::Token![=>] = input.parse()?;
+    Ok(VariantMode::Arm(pattern, parse_expr_tokens(input)?))
+}
 
-                    // Parse everything after "arm =" as tokens
-                    let mut tokens = TokenStream2::new();
-                    while !parser.is_empty() {
-                        tokens.extend(TokenStream2::from(
-                            parser.parse::<proc_macro2::TokenTree>()?,
-                        ));
-                    }
+/// Parse the arguments of `#[polyquine_with(arm = (pattern) => body)]`.
+/// The body evaluates to the `TokenStream` constructing the variant.
+fn parse_legacy_arm(parser: ParseStream) -> syn::Result<VariantMode> {
+    // Parse "arm ="
+    let ident: syn::Ident = parser.parse()?;
+    if ident != "arm" {
+        return Err(syn::Error::new(
+            ident.span(),
+            "expected `arm = (pattern) => body`",
+        ));
+    }
 
-                    Ok(tokens)
-                })
-                .ok();
+    let _: syn::Token![=] = parser.parse()?;
+
+    // Parse everything up to "=>" as the pattern
+    let mut pattern = TokenStream2::new();
+    while !parser.peek(syn::Token![=>]) {
+        if parser.is_empty() {
+            return Err(parser.error("expected `=>`"));
+        }
+        pattern.extend(TokenStream2::from(
+            parser.parse::<proc_macro2::TokenTree>()?,
+        ));
+    }
+
+    let _: syn::Token![=>] = parser.parse()?;
+
+    // Parse everything after "=>" as the body
+    let mut body = TokenStream2::new();
+    while !parser.is_empty() {
+        body.extend(TokenStream2::from(
+            parser.parse::<proc_macro2::TokenTree>()?,
+        ));
+    }
+    if body.is_empty() {
+        return Err(parser.error("expected the body of the arm after `=>`"));
+    }
+
+    Ok(VariantMode::Arm(pattern, body))
+}
+
+/// The attributes replaced by `#[polyquine(..)]`, and what to use instead.
+const DEPRECATED: [(&str, &str); 4] = [
+    ("path_prefix", "use `#[polyquine(prefix = ..)]` instead"),
+    ("polyquine_path", "use `#[polyquine(path(..))]` instead"),
+    ("polyquine_skip", "use `#[polyquine(skip)]` instead"),
+    (
+        "polyquine_with",
+        "use `#[polyquine(with = (pattern) => body)]` instead",
+    ),
+];
+
+/// Deprecation warnings for the attributes in [`DEPRECATED`].
+/// Proc macros cannot emit warnings on stable, so each one is a use of a `#[deprecated]` item, spanned at the attribute.
+fn deprecations(input: &DeriveInput) -> TokenStream2 {
+    let mut attrs: Vec<&syn::Attribute> = input.attrs.iter().collect();
+    if let Data::Enum(data) = &input.data {
+        attrs.extend(data.variants.iter().flat_map(|variant| &variant.attrs));
+    }
+    let mut warnings = TokenStream2::new();
+    for attr in attrs {
+        for (name, note) in DEPRECATED {
+            if attr.path().is_ident(name) {
+                let item = Ident::new(name, attr.path().span());
+                warnings.extend(quote::quote_spanned! {attr.path().span()=>
+                    const _: () = {
+                        #[deprecated(note = #note)]
+                        #[allow(non_camel_case_types)]
+                        struct #item;
+                        let _ = #item;
+                    };
+                });
+            }
         }
     }
-    None
+    warnings
 }
 
-#[proc_macro_derive(Quine, attributes(path_prefix, polyquine_skip, polyquine_with))]
+/// The attributes of the derive, and the items each of them can be used on.
+const ATTRIBUTES: [(&str, &[&str]); 5] = [
+    ("path_prefix", &["types"]),
+    ("polyquine", &["types", "enum variants", "fields"]),
+    ("polyquine_path", &["types"]),
+    ("polyquine_skip", &["enum variants"]),
+    ("polyquine_with", &["enum variants"]),
+];
+
+/// Reject the attributes of the derive that cannot be used on `place`, e.g. `#[polyquine_skip]` on a field.
+fn check_placement(attrs: &[syn::Attribute], place: &str) -> syn::Result<()> {
+    for attr in attrs {
+        for (name, allowed) in ATTRIBUTES {
+            if attr.path().is_ident(name) && !allowed.contains(&place) {
+                return Err(syn::Error::new_spanned(
+                    attr.path(),
+                    format!(
+                        "`{name}` cannot be used on {place}, only on {}",
+                        allowed.join(" and ")
+                    ),
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Check the placement of the attributes on the type, its variants and its fields.
+fn check_attrs(input: &DeriveInput) -> syn::Result<()> {
+    check_placement(&input.attrs, "types")?;
+    let fields: Vec<&syn::Field> = match &input.data {
+        Data::Struct(data) => data.fields.iter().collect(),
+        Data::Enum(data) => {
+            for variant in &data.variants {
+                check_placement(&variant.attrs, "enum variants")?;
+            }
+            data.variants
+                .iter()
+                .flat_map(|variant| &variant.fields)
+                .collect()
+        }
+        Data::Union(data) => data.fields.named.iter().collect(),
+    };
+    for field in fields {
+        check_placement(&field.attrs, "fields")?;
+    }
+    Ok(())
+}
+
+#[proc_macro_derive(
+    Quine,
+    attributes(path_prefix, polyquine, polyquine_path, polyquine_skip, polyquine_with)
+)]
 pub fn derive_quine(input: TokenStream) -> TokenStream {
-    let input: DeriveInput = syn::parse2(input.clone().into()).expect(format!("Could not parse macro input: {input}").as_str());
+    let input = syn::parse_macro_input!(input as DeriveInput);
+    if let Err(err) = check_attrs(&input) {
+        return err.to_compile_error().into();
+    }
+    let deprecations = deprecations(&input);
     let mut generics = input.generics;
     let ident = input.ident;
 
-    // Add "T: ... + Quine" to the where clause for types with generics
-    generics.where_clause = build_where_clause(&generics);
+    // Parse the polyquine(..) attributes: crate = .., prefix = .., path(..), ctor = .., via = .., hidden_ctor, with = .., unsafe_active = .., bound = ..
+    let ContainerAttrs {
+        krate,
+        prefix,
+        crate_path,
+        mut ctor,
+        via,
+        hidden_ctor,
+        with,
+        active,
+        bound,
+        konst,
+    } = match parse_container_attrs(&input.attrs) {
+        Ok(attrs) => attrs,
+        Err(err) => return err.to_compile_error().into(),
+    };
+    let replaced = match (&with, &via) {
+        (Some(_), _) => Some("`with` a function"),
+        (None, Some(_)) => Some("`via` another type"),
+        (None, None) => None,
+    };
+    if let Err(err) = check_field_attrs(&input.data, replaced, active.as_ref()) {
+        return err.to_compile_error().into();
+    }
+    if konst && let Err(err) = check_const_fields(&input.data) {
+        return err.to_compile_error().into();
+    }
+
+    // Generate the hidden constructor before the Quine bounds are added to the generics
+    let hidden_ctor = if hidden_ctor {
+        let Data::Struct(data) = &input.data else {
+            return syn::Error::new(ident.span(), "`hidden_ctor` is only supported on structs")
+                .to_compile_error()
+                .into();
+        };
+        ctor = Some((syn::parse_quote!(Self::__polyquine_new), None));
+        build_hidden_ctor(&ident, &generics, &data.fields)
+    } else {
+        TokenStream2::new()
+    };
+
+    // Add the bounds given by polyquine(bound = ..), or "T: Quine" for the type parameters of the emitted fields
+    let predicates = match bound {
+        Some(bound) => bound,
+        None => {
+            let inferred = emitted_fields(
+                &input.data,
+                ctor.as_ref(),
+                with.is_some() || via.is_some(),
+                active.as_ref(),
+            )
+            .and_then(|fields| infer_bounds(&krate, &generics, fields));
+            match inferred {
+                Ok(mut predicates) => {
+                    if let Some(via) = &via {
+                        predicates.push(syn::parse_quote!(#via: #krate::Quine));
+                    }
+                    predicates
+                }
+                Err(err) => return err.to_compile_error().into(),
+            }
+        }
+    };
+    generics.where_clause = build_where_clause(&generics, predicates);
 
-    // Parse the path_prefix attribute, if any
-    let mut module_prefix: Option<Path> = None;
+    // Parse the deprecated path_prefix attribute, if any
+    let mut module_prefix = prefix;
     for attr in &input.attrs {
         if attr.path().is_ident("path_prefix") {
             if module_prefix.is_some() {
-                return syn::Error::new(attr.span(), "duplicate path_prefix attribute")
-                    .to_compile_error()
-                    .into();
+                return syn::Error::new_spanned(
+                    attr,
+                    "duplicate path prefix, from `path_prefix` or `polyquine(prefix = ..)`",
+                )
+                .to_compile_error()
+                .into();
             }
             match attr.parse_args::<Path>() {
                 Ok(path) => module_prefix = Some(path),
@@ -134,167 +1132,385 @@ pub fn derive_quine(input: TokenStream) -> TokenStream {
         }
     }
 
-    let path_setup = build_path_setup(&ident, module_prefix.as_ref());
+    // Parse the deprecated polyquine_path attribute, if any
+    let mut crate_path = crate_path;
+    for attr in &input.attrs {
+        if attr.path().is_ident("polyquine_path") {
+            if crate_path.is_some() {
+                return syn::Error::new_spanned(
+                    attr,
+                    "duplicate crate path, from `polyquine_path` or `polyquine(path(..))`",
+                )
+                .to_compile_error()
+                .into();
+            }
+            match attr.parse_args_with(parse_crate_path) {
+                Ok(path) => crate_path = Some(path),
+                Err(err) => return err.to_compile_error().into(),
+            }
+        }
+    }
+    let crate_path = match &crate_path {
+        Some(crate_path) => crate_path.to_expr(&krate),
+        None => quote! { #krate::CratePath::Name },
+    };
+
+    let (path_items, path_expr) =
+        build_path_expr(&krate, &ident, module_prefix.as_ref(), &crate_path);
+    let type_segment = quote! { #krate::PathSegment::Type(stringify!(#ident)) };
     let (impl_gen, ty_gen, where_clause) = generics.split_for_impl();
 
-    let body =
-        match input.data {
-            // Derive for structs
-            Data::Struct(data) => match &data.fields {
-                Fields::Unit => {
-                    let path = hash_ident(&Ident::new(&"path", proc_macro2::Span::call_site()));
-                    quote! {
-                        #path_setup
-                        ::quote::quote!{#path {}}
-                    }
-                }
-                Fields::Unnamed(fields) => {
-                    let (decls, exps): (Vec<TokenStream2>, Vec<TokenStream2>) = fields
-                        .unnamed
-                        .iter()
-                        .enumerate()
-                        .map(|(i, f)| {
-                            let idnt = Ident::new(format!("gen_field_{}", i).as_str(), f.span());
-                            let idx = Index::from(i);
-                            let field_let = quote! {
-                                let #idnt = self.#idx.ctor_tokens();
-                            };
-                            (field_let, hash_ident(&idnt))
-                        })
-                        .unzip();
-                    let path = hash_ident(&Ident::new(&"path", proc_macro2::Span::call_site()));
-                    quote! {
-                        #(#decls)*
-                        #path_setup
-                        ::quote::quote!{#path(#(#exps),*)}
-                    }
-                }
-                Fields::Named(fields) => {
-                    let (decls, exps): (Vec<TokenStream2>, Vec<TokenStream2>) = fields
-                        .named
-                        .iter()
-                        .map(|f| {
-                            let f_toks = f.to_token_stream().to_string();
-                            let ident = f.ident.as_ref().expect(format!("Could not get ident of named struct field {f_toks}").as_str());
-                            let field_let = quote! {
-                                let #ident = self.#ident.ctor_tokens();
-                            };
-                            let hash_ident = hash_ident(ident);
-                            let field_exp = quote! {#ident: #hash_ident};
-                            (field_let, field_exp)
-                        })
-                        .unzip();
-                    let path = hash_ident(&Ident::new(&"path", proc_macro2::Span::call_site()));
-                    quote! {
-                        #(#decls)*
-                        #path_setup
-                        ::quote::quote!{#path{#(#exps),*}}
-                    }
-                }
+    // The type's path in constructors, followed by its type arguments with `TypeArgs::Turbofish`
+    let type_args = type_args(&krate, &generics);
+    let (ctor_path, type_tokens) = if type_args.is_empty() {
+        (
+            path_expr.clone(),
+            quote! {
+                out.extend(#path_expr);
+                ::core::result::Result::Ok(())
             },
-            Data::Enum(data) => {
-                // Derive for enums
-                let arms = data.variants.iter().map(|v| {
-                let variant_ident = &v.ident;
+        )
+    } else {
+        let ctor_path = quote! {{
+            let mut path = #path_expr;
+            #krate::__private::append_turbofish(ctx, &mut path, &[#(#type_args),*])?;
+            path
+        }};
+        (
+            ctor_path,
+            quote! {
+                out.extend(#path_expr);
+                #krate::__private::append_type_args(ctx, out, &[#(#type_args),*])
+            },
+        )
+    };
 
-                // Skipped enum variants
-                let is_skipped = v.attrs.iter().any(|attr| attr.path().is_ident("polyquine_skip"));
-                if is_skipped {
-                    let skipped_msg = quote! {
-                        {
-                            panic!("Attempted to call ctor_tokens() on skipped enum variant {}::{}",
-                                   stringify!(#ident), stringify!(#variant_ident))
-                        }
+    let body = match input.data {
+        // Derive for structs
+        _ if with.is_some() => {
+            let with = with.as_ref().unwrap();
+            quote! {
+                emitter.non_const(::core::any::type_name::<Self>())?;
+                emitter.append(|out| out.extend(#with(self)));
+                ::core::result::Result::Ok(())
+            }
+        }
+        Data::Struct(_) | Data::Enum(_) if active.is_some() => {
+            let active = active.as_ref().unwrap();
+            return syn::Error::new(active.span(), "`unsafe_active` is only supported on unions")
+                .to_compile_error()
+                .into();
+        }
+        _ if via.is_some() => {
+            let via = via.as_ref().unwrap();
+            quote! {
+                emitter.enter(#type_segment);
+                emitter.non_const(::core::any::type_name::<Self>())?;
+                emitter.try_append(|out| {
+                    out.extend(#ctor_path);
+                    #krate::__private::append_segment(out, "from");
+                    ::core::result::Result::Ok(())
+                })?;
+                emitter.open(#krate::__private::proc_macro2::Delimiter::Parenthesis);
+                let proxy = <#via as ::core::convert::From<&Self>>::from(self);
+                emitter.borrowed(&proxy)?;
+                emitter.close();
+                emitter.leave();
+                ::core::result::Result::Ok(())
+            }
+        }
+        Data::Struct(data) => {
+            let fields = match &data.fields {
+                Fields::Unit => Ok(Vec::new()),
+                Fields::Unnamed(fields) => fields
+                    .unnamed
+                    .iter()
+                    .enumerate()
+                    .map(|(i, f)| {
+                        let idx = Index::from(i);
+                        Ok((
+                            syn::Member::Unnamed(idx.clone()),
+                            EmitField {
+                                name: None,
+                                expr: quote! { &self.#idx },
+                                segment: tuple_field_segment(&krate, i),
+                                mode: parse_field_mode(f)?,
+                            },
+                        ))
+                    })
+                    .collect::<syn::Result<Vec<_>>>(),
+                Fields::Named(fields) => fields
+                    .named
+                    .iter()
+                    .map(|f| {
+                        let ident = f.ident.as_ref().expect("named fields have an ident");
+                        Ok((
+                            syn::Member::Named(ident.clone()),
+                            EmitField {
+                                name: Some(ident.to_string()),
+                                expr: quote! { &self.#ident },
+                                segment: named_field_segment(&krate, ident),
+                                mode: parse_field_mode(f)?,
+                            },
+                        ))
+                    })
+                    .collect::<syn::Result<Vec<_>>>(),
+            };
+            let fields = match fields {
+                Ok(fields) => fields,
+                Err(err) => return err.to_compile_error().into(),
+            };
+            let (head, fields) = match &ctor {
+                Some((ctor, args)) => {
+                    let args = match select_args(fields, args.as_deref()) {
+                        Ok(args) => args,
+                        Err(err) => return err.to_compile_error().into(),
                     };
-                   return match &v.fields {
-                        Fields::Unit => quote! {#ident::#variant_ident => #skipped_msg},
-                        Fields::Unnamed(_) => quote! {#ident::#variant_ident(..) => #skipped_msg},
-                        Fields::Named(_) => quote! {#ident::#variant_ident{..} => #skipped_msg},
+                    (
+                        ctor_path_expr(&krate, &ident, ctor, &ctor_path),
+                        emit_fields(&krate, "Parenthesis", konst, args),
+                    )
+                }
+                None => {
+                    let delimiter = match &data.fields {
+                        Fields::Unnamed(_) => "Parenthesis",
+                        _ => "Brace",
                     };
+                    (
+                        ctor_path.clone(),
+                        emit_fields(
+                            &krate,
+                            delimiter,
+                            konst,
+                            fields.into_iter().map(|(_, field)| field),
+                        ),
+                    )
                 }
-
-                // Custom arm for enum variant
-                if let Some(custom_arm) = parse_custom_arm(v) {
-                    return quote! {#ident::#variant_ident #custom_arm};
+            };
+            // A `ctor` is a function that may not be `const`
+            let non_const = ctor
+                .is_some()
+                .then(|| quote! { emitter.non_const(::core::any::type_name::<Self>())?; });
+            quote! {
+                emitter.enter(#type_segment);
+                #non_const
+                emitter.try_append(|out| {
+                    out.extend(#head);
+                    ::core::result::Result::Ok(())
+                })?;
+                #fields
+                emitter.leave();
+                ::core::result::Result::Ok(())
+            }
+        }
+        Data::Enum(_) if ctor.is_some() => {
+            return syn::Error::new(
+                ident.span(),
+                "`ctor` is only supported on structs, use `via` for enums",
+            )
+            .to_compile_error()
+            .into();
+        }
+        Data::Enum(data) => {
+            // Derive for enums
+            // The arms of a `const` enum are trusted to be `const`, like the expressions given for fields
+            let non_const =
+                (!konst).then(|| quote! { emitter.non_const(::core::any::type_name::<Self>())?; });
+            let arms = data.variants.iter().map(|v| -> syn::Result<TokenStream2> {
+                let variant_ident = &v.ident;
+                let variant_name = variant_ident.to_string();
+                let variant_segment = quote! {
+                    #krate::PathSegment::Variant {
+                        ty: stringify!(#ident),
+                        variant: stringify!(#variant_ident),
+                    }
                 };
 
-                match &v.fields {
-                    Fields::Unit => {
-                        let path = hash_ident(&Ident::new(&"path", proc_macro2::Span::call_site()));
-                        quote! {#ident::#variant_ident => {
-                            #path_setup
-                            ::quote::quote!{#path::#variant_ident}
-                        }}
+                // Matches the variant whatever its fields
+                let rest = match &v.fields {
+                    Fields::Unit => TokenStream2::new(),
+                    Fields::Unnamed(_) => quote! { (..) },
+                    Fields::Named(_) => quote! { {..} },
+                };
+                match parse_variant_mode(v)? {
+                    VariantMode::Quine => {}
+                    // Skipped enum variants
+                    VariantMode::Skip => {
+                        return Ok(quote! {
+                            #ident::#variant_ident #rest => ::core::result::Result::Err(#krate::QuineError::skipped_variant(
+                                stringify!(#ident),
+                                stringify!(#variant_ident),
+                            ).with_segment(#variant_segment))
+                        });
+                    }
+                    // Custom arm for enum variant
+                    VariantMode::Arm(pattern, body) => {
+                        return Ok(quote! {
+                            #ident::#variant_ident #pattern => {
+                                #non_const
+                                emitter.append(|out| out.extend(#body));
+                                ::core::result::Result::Ok(())
+                            }
+                        });
                     }
+                    VariantMode::With(path) => {
+                        return Ok(quote! {
+                            #ident::#variant_ident #rest => {
+                                #non_const
+                                emitter.append(|out| out.extend(#path(self)));
+                                ::core::result::Result::Ok(())
+                            }
+                        });
+                    }
+                }
+
+                let (pattern, fields) = match &v.fields {
+                    Fields::Unit => (TokenStream2::new(), TokenStream2::new()),
                     Fields::Unnamed(fields) => {
                         let mut binds: Vec<Ident> = Vec::new();
-                        let mut decls: Vec<TokenStream2> = Vec::new();
-                        let mut exps: Vec<TokenStream2> = Vec::new();
+                        let mut emits = Vec::new();
                         for (i, f) in fields.unnamed.iter().enumerate() {
-                            let ident = Ident::new(format!("gen_field_{}", i).as_str(), f.span());
-                            let exp_ident =
-                                Ident::new(format!("gen_field_{}_exp", i).as_str(), f.span());
-                            let field_let = quote! {
-                                let #exp_ident = #ident.ctor_tokens();
-                            };
-                            binds.push(ident);
-                            decls.push(field_let);
-                            exps.push(hash_ident(&exp_ident));
-                        }
-                        let path = hash_ident(&Ident::new(&"path", proc_macro2::Span::call_site()));
-                        quote! {
-                            #ident::#variant_ident(#(#binds),*) => {
-                                #(#decls)*
-                                #path_setup
-                                ::quote::quote!{#path::#variant_ident(#(#exps),*)}
-                            }
+                            let bind = Ident::new(format!("gen_field_{}", i).as_str(), f.span());
+                            let mode = parse_field_mode(f)?;
+                            emits.push(EmitField {
+                                name: None,
+                                expr: quote! { #bind },
+                                segment: tuple_field_segment(&krate, i),
+                                mode,
+                            });
+                            binds.push(bind);
                         }
+                        (quote! { (#(#binds),*) }, emit_fields(&krate, "Parenthesis", konst, emits))
                     }
                     Fields::Named(fields) => {
-                        let mut binds: Vec<Ident> = Vec::new();
-                        let mut decls: Vec<TokenStream2> = Vec::new();
-                        let mut exps: Vec<TokenStream2> = Vec::new();
-                        for f in fields.named.iter() {
-                            let f_toks = f.to_token_stream().to_string();
-                            let ident = f.ident.as_ref().expect(format!("Could not get ident of named enum field {f_toks}").as_str());
-                            let exp_ident =
-                                Ident::new(format!("gen_field_{}_exp", ident).as_str(), f.span());
-                            let field_let = quote! {
-                                let #exp_ident = #ident.ctor_tokens();
-                            };
-                            let hash_ident = hash_ident(&exp_ident);
-
-                            binds.push(ident.clone());
-                            decls.push(field_let);
-                            exps.push(quote! { #ident: #hash_ident });
-                        }
-                        let path = hash_ident(&Ident::new(&"path", proc_macro2::Span::call_site()));
-                        quote! {
-                            #ident::#variant_ident{#(#binds),*} => {
-                                #(#decls)*
-                                #path_setup
-                                ::quote::quote!{#path::#variant_ident{#(#exps),*}}
-                            }
+                        let mut binds: Vec<TokenStream2> = Vec::new();
+                        let mut emits = Vec::new();
+                        for (i, f) in fields.named.iter().enumerate() {
+                            let ident = f.ident.as_ref().expect("named fields have an ident");
+                            let bind_ident = Ident::new(format!("gen_field_{}", i).as_str(), f.span());
+                            let mode = parse_field_mode(f)?;
+
+                            binds.push(quote! { #ident: #bind_ident });
+                            emits.push(EmitField {
+                                name: Some(ident.to_string()),
+                                expr: quote! { #bind_ident },
+                                segment: named_field_segment(&krate, ident),
+                                mode,
+                            });
                         }
+                        (quote! { {#(#binds),*} }, emit_fields(&krate, "Brace", konst, emits))
                     }
-                }
-            });
-                quote! {
-                    match self {
-                        #(#arms),*
+                };
+                Ok(quote! {
+                    #ident::#variant_ident #pattern => {
+                        emitter.enter(#variant_segment);
+                        emitter.try_append(|out| {
+                            out.extend(#ctor_path);
+                            #krate::__private::append_segment(out, #variant_name);
+                            ::core::result::Result::Ok(())
+                        })?;
+                        #fields
+                        emitter.leave();
+                        ::core::result::Result::Ok(())
                     }
+                })
+            });
+            let arms = match arms.collect::<syn::Result<Vec<_>>>() {
+                Ok(arms) => arms,
+                Err(err) => return err.to_compile_error().into(),
+            };
+            quote! {
+                match self {
+                    #(#arms),*
                 }
             }
-            Data::Union(_) => {
-                unimplemented!("Unions are not supported")
+        }
+        Data::Union(data) => {
+            let Some(active) = &active else {
+                return syn::Error::new(
+                        data.union_token.span,
+                        "unions need `#[polyquine(unsafe_active = field)]` or `#[polyquine(with = function)]`",
+                    )
+                    .to_compile_error()
+                    .into();
+            };
+            if ctor.is_some() {
+                return syn::Error::new(
+                    ident.span(),
+                    "`ctor` is only supported on structs, use `via` for unions",
+                )
+                .to_compile_error()
+                .into();
+            }
+            let Some(field) = data
+                .fields
+                .named
+                .iter()
+                .find(|f| f.ident.as_ref() == Some(active))
+            else {
+                return syn::Error::new(active.span(), "no such field")
+                    .to_compile_error()
+                    .into();
+            };
+            let mode = match parse_field_mode(field) {
+                Ok(mode) => mode,
+                Err(err) => return err.to_compile_error().into(),
+            };
+            let fields = emit_fields(
+                &krate,
+                "Brace",
+                konst,
+                [EmitField {
+                    name: Some(active.to_string()),
+                    // SAFETY: `#[polyquine(unsafe_active = field)]` asserts that this field is initialized in every value
+                    expr: quote! { unsafe { &self.#active } },
+                    segment: named_field_segment(&krate, active),
+                    mode,
+                }],
+            );
+            quote! {
+                emitter.enter(#type_segment);
+                emitter.try_append(|out| {
+                    out.extend(#ctor_path);
+                    ::core::result::Result::Ok(())
+                })?;
+                #fields
+                emitter.leave();
+                ::core::result::Result::Ok(())
             }
-        };
+        }
+    };
 
     let ans = quote! {
-        impl #impl_gen Quine for #ident #ty_gen #where_clause {
-            fn ctor_tokens(&self) -> ::proc_macro2::TokenStream {
+        #deprecations
+        #hidden_ctor
+
+        impl #impl_gen #krate::Quine for #ident #ty_gen #where_clause {
+            fn try_to_ctor_tokens_with(
+                &self,
+                ctx: &#krate::QuineContext,
+                out: &mut #krate::__private::proc_macro2::TokenStream,
+            ) -> ::core::result::Result<(), #krate::QuineError> {
+                #krate::Emitter::run(self, ctx, out)
+            }
+
+            #[allow(unused_variables)]
+            fn emit<'polyquine>(
+                &'polyquine self,
+                emitter: &mut #krate::Emitter<'polyquine>,
+            ) -> ::core::result::Result<(), #krate::QuineError> {
+                #path_items
+                let ctx = emitter.ctx();
                 #body
             }
+
+            fn try_to_type_tokens_with(
+                ctx: &#krate::QuineContext,
+                out: &mut #krate::__private::proc_macro2::TokenStream,
+            ) -> ::core::result::Result<(), #krate::QuineError> {
+                #path_items
+                #type_tokens
+            }
         }
     };
     ans.into()
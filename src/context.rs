@@ -0,0 +1,300 @@
+use std::collections::BTreeMap;
+
+use proc_macro2::{Punct, Spacing, TokenStream};
+use quote::TokenStreamExt;
+
+use crate::CratePath;
+use crate::tokens::{append_ident, append_segment};
+
+/// Options controlling the shape of the tokens generated by [`Quine`](crate::Quine).
+///
+/// Pass it to [`Quine::ctor_tokens_with`](crate::Quine::ctor_tokens_with);
+/// [`Quine::ctor_tokens`](crate::Quine::ctor_tokens) uses `QuineContext::default()`.
+///
+/// ```
+/// use polyquine::{Quine, QuineContext, SequenceStyle};
+///
+/// let ctx = QuineContext::new().with_sequences(SequenceStyle::Macro);
+/// assert_eq!(vec![1i32].ctor_tokens_with(&ctx).to_string(), ":: std :: vec ! [1i32]");
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct QuineContext {
+    type_paths: TypePaths,
+    std_paths: StdPaths,
+    sequences: SequenceStyle,
+    map_order: MapOrder,
+    nesting: Nesting,
+    dedup: Dedup,
+    cycles: Cycles,
+    type_args: TypeArgs,
+    crate_paths: BTreeMap<String, CratePath>,
+}
+
+/// How the paths of types that derive `Quine` are emitted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TypePaths {
+    /// `#[polyquine(prefix = ..)]::Type` if the type has a path prefix,
+    /// otherwise `module_path!()::Type` as seen from the type's definition,
+    /// with the defining crate named according to its [`CratePath`].
+    #[default]
+    Qualified,
+    /// Just the type's name; it must be in scope wherever the tokens are used.
+    Unqualified,
+}
+
+/// How items from `std` (e.g. `Box`, `Some`, `Vec`) are emitted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StdPaths {
+    /// `Box::new(..)`, `Some(..)`; relies on the prelude and `use`s at the call site.
+    Unqualified,
+    /// `::std::boxed::Box::new(..)`, `::core::option::Option::Some(..)`.
+    #[default]
+    Std,
+    /// `::alloc::boxed::Box::new(..)`, `::core::option::Option::Some(..)`,
+    /// for `no_std` crates; they must declare `extern crate alloc;`.
+    /// `HashMap` and `HashSet` are only available in `std` and are still emitted as `::std::..`.
+    NoStd,
+}
+
+/// How a `Vec` is constructed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SequenceStyle {
+    /// `Vec::from([..])`
+    #[default]
+    From,
+    /// `vec![..]`
+    Macro,
+}
+
+/// In which order the entries of a `HashMap` or `HashSet` are emitted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MapOrder {
+    /// Hash iteration order, which may differ between runs.
+    /// Faster, for output that does not need to be reproducible.
+    Iteration,
+    /// A stable order, so the output is the same on every run:
+    /// sorted by key with [`Quine::quine_cmp`](crate::Quine::quine_cmp) if the key type has an order,
+    /// e.g. integers and strings, otherwise by the string form of each key's tokens.
+    /// If the keys have no order and cannot be emitted on their own, e.g. because they hold a `Weak`,
+    /// this fails with [`QuineErrorKind::UnsortableKey`](crate::QuineErrorKind::UnsortableKey).
+    #[default]
+    Sorted,
+}
+
+/// Whether nested values are emitted as one nested expression.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Nesting {
+    /// A single expression, however deep the value is.
+    #[default]
+    Nested,
+    /// A block of `let __q_N = ..;` bindings followed by the final expression,
+    /// such that no expression contains values nested more than `max_depth` levels deep.
+    /// Values at that depth are bound to a temporary instead.
+    ///
+    /// Deeply nested expressions can exceed rustc's `recursion_limit` and are slow to type check.
+    Flatten { max_depth: usize },
+}
+
+/// Whether equal subtrees of a value are emitted once.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Dedup {
+    /// Every subtree is emitted in full wherever it occurs.
+    #[default]
+    Off,
+    /// Subtrees of the same type with the same tokens that occur more than once are bound to a `let __q_N = ..;`
+    /// and emitted as `__q_N.clone()` at every use, when that makes the output shorter.
+    /// Their types must implement `Clone`.
+    ///
+    /// [`Emitter::run_with_report`](crate::Emitter::run_with_report) tells how much shorter the output got.
+    Subtrees,
+}
+
+/// What happens when a value refers back to an `Rc` or `Arc` that contains it, through a `Weak`.
+///
+/// A cycle of strong references cannot be rebuilt and is always an error.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Cycles {
+    /// Return a [`QuineErrorKind::Cycle`](crate::QuineErrorKind::Cycle) error.
+    #[default]
+    Error,
+    /// Build the allocation with `Rc::new_cyclic(|__weak_N| ..)`, and emit the `Weak`s pointing back to it
+    /// as `Weak::clone(__weak_N)`, e.g. for parent pointers in a tree of `Rc<RefCell<Node>>`.
+    ///
+    /// `let`s that depend on `__weak_N` are placed inside the closure;
+    /// an allocation bound there cannot be shared with values outside of it.
+    NewCyclic,
+}
+
+/// Whether the constructors of generic types are given their type arguments.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TypeArgs {
+    /// `Vec::from([])`, `None`, `Test { .. }`; the type arguments are left to inference at the use site.
+    #[default]
+    Inferred,
+    /// `Vec::<i32>::from([])`, `None::<i32>`, `Test::<String> { .. }`,
+    /// for code where they cannot be inferred, e.g. a `let` without a type annotation.
+    /// The type arguments must implement [`Quine::try_to_type_tokens_with`](crate::Quine::try_to_type_tokens_with);
+    /// `Vec`s are emitted as `Vec::<T>::from([..])` even with [`SequenceStyle::Macro`].
+    Turbofish,
+}
+
+/// Items from `std` that the built-in `Quine` implementations refer to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum StdItem {
+    Box,
+    Option,
+    Some,
+    None,
+    String,
+    Vec,
+    VecMacro,
+    VecDeque,
+    HashMap,
+    HashSet,
+    BTreeMap,
+    Rc,
+    RcWeak,
+    Arc,
+    ArcWeak,
+    RefCell,
+    PhantomData,
+    LazyLock,
+    // Not from `std`, but referred to in the same way
+    Ustr,
+}
+
+impl QuineContext {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_type_paths(mut self, type_paths: TypePaths) -> Self {
+        self.type_paths = type_paths;
+        self
+    }
+
+    pub fn with_std_paths(mut self, std_paths: StdPaths) -> Self {
+        self.std_paths = std_paths;
+        self
+    }
+
+    pub fn with_sequences(mut self, sequences: SequenceStyle) -> Self {
+        self.sequences = sequences;
+        self
+    }
+
+    pub fn with_map_order(mut self, map_order: MapOrder) -> Self {
+        self.map_order = map_order;
+        self
+    }
+
+    pub fn with_nesting(mut self, nesting: Nesting) -> Self {
+        self.nesting = nesting;
+        self
+    }
+
+    pub fn with_dedup(mut self, dedup: Dedup) -> Self {
+        self.dedup = dedup;
+        self
+    }
+
+    pub fn with_cycles(mut self, cycles: Cycles) -> Self {
+        self.cycles = cycles;
+        self
+    }
+
+    pub fn with_type_args(mut self, type_args: TypeArgs) -> Self {
+        self.type_args = type_args;
+        self
+    }
+
+    /// Emit the paths of types defined in the crate `crate_name` according to `path`.
+    /// This takes precedence over the types' own `#[polyquine(path(..))]` attributes.
+    ///
+    /// `crate_name` is the name of the defining crate as it appears in its `module_path!()`,
+    /// i.e. its package name with `-` replaced by `_`.
+    pub fn with_crate_path(mut self, crate_name: impl Into<String>, path: CratePath) -> Self {
+        self.crate_paths.insert(crate_name.into(), path);
+        self
+    }
+
+    pub fn type_paths(&self) -> TypePaths {
+        self.type_paths
+    }
+
+    pub fn std_paths(&self) -> StdPaths {
+        self.std_paths
+    }
+
+    pub fn sequences(&self) -> SequenceStyle {
+        self.sequences
+    }
+
+    pub fn map_order(&self) -> MapOrder {
+        self.map_order
+    }
+
+    pub fn nesting(&self) -> Nesting {
+        self.nesting
+    }
+
+    pub fn dedup(&self) -> Dedup {
+        self.dedup
+    }
+
+    pub fn cycles(&self) -> Cycles {
+        self.cycles
+    }
+
+    pub fn type_args(&self) -> TypeArgs {
+        self.type_args
+    }
+
+    /// How the paths of types defined in the crate `crate_name` are emitted, if set by [`QuineContext::with_crate_path`].
+    pub fn crate_path(&self, crate_name: &str) -> Option<&CratePath> {
+        self.crate_paths.get(crate_name)
+    }
+
+    /// Append the path of `item` to `out`.
+    pub(crate) fn append_std_path(&self, item: StdItem, out: &mut TokenStream) {
+        // Items that live in `alloc` are re-exported by `std` under the same path
+        let alloc = match self.std_paths {
+            StdPaths::NoStd => "alloc",
+            _ => "std",
+        };
+        let (root, segments): (&str, &[&str]) = match item {
+            StdItem::Box => (alloc, &["boxed", "Box"]),
+            StdItem::Option => ("core", &["option", "Option"]),
+            StdItem::Some => ("core", &["option", "Option", "Some"]),
+            StdItem::None => ("core", &["option", "Option", "None"]),
+            StdItem::String => (alloc, &["string", "String"]),
+            StdItem::Vec => (alloc, &["vec", "Vec"]),
+            StdItem::VecMacro => (alloc, &["vec"]),
+            StdItem::VecDeque => (alloc, &["collections", "VecDeque"]),
+            StdItem::HashMap => ("std", &["collections", "HashMap"]),
+            StdItem::HashSet => ("std", &["collections", "HashSet"]),
+            StdItem::BTreeMap => (alloc, &["collections", "BTreeMap"]),
+            StdItem::Rc => (alloc, &["rc", "Rc"]),
+            StdItem::RcWeak => (alloc, &["rc", "Weak"]),
+            StdItem::Arc => (alloc, &["sync", "Arc"]),
+            StdItem::ArcWeak => (alloc, &["sync", "Weak"]),
+            StdItem::RefCell => ("core", &["cell", "RefCell"]),
+            StdItem::PhantomData => ("core", &["marker", "PhantomData"]),
+            // Only in `std`, whatever the `StdPaths`
+            StdItem::LazyLock => ("std", &["sync", "LazyLock"]),
+            StdItem::Ustr => ("ustr", &["Ustr"]),
+        };
+
+        if self.std_paths == StdPaths::Unqualified {
+            let name = segments.last().expect("every item has a name");
+            append_ident(out, name);
+        } else {
+            for segment in std::iter::once(&root).chain(segments) {
+                append_segment(out, segment);
+            }
+        }
+        if item == StdItem::VecMacro {
+            out.append(Punct::new('!', Spacing::Alone));
+        }
+    }
+}
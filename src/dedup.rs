@@ -0,0 +1,276 @@
+use std::collections::{HashMap, HashSet};
+
+use proc_macro2::{Delimiter, Group, Ident, Span, TokenStream, TokenTree, token_stream};
+use quote::{TokenStreamExt, quote};
+
+use crate::Nesting;
+
+/// How much smaller [`Dedup::Subtrees`](crate::Dedup::Subtrees) made the tokens of a value.
+/// Returned by [`Emitter::run_with_report`](crate::Emitter::run_with_report).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DedupReport {
+    /// Token trees in the output without deduplication, counting each group as one plus its contents
+    pub tokens_before: usize,
+    /// Token trees in the output
+    pub tokens_after: usize,
+    /// Distinct subtrees that were bound to a `let` and cloned at each use
+    pub shared: usize,
+}
+
+impl DedupReport {
+    /// The fraction of the tokens that deduplication saved, between `0.0` and `1.0`.
+    pub fn reduction(&self) -> f64 {
+        if self.tokens_before == 0 {
+            return 0.0;
+        }
+        1.0 - self.tokens_after as f64 / self.tokens_before as f64
+    }
+}
+
+const PLACEHOLDER_PREFIX: &str = "__polyquine_node_";
+
+/// Hash-conses the values found during emission.
+///
+/// Each value is stored once per type and distinct token output, with its nested values replaced by placeholders,
+/// so equal subtrees get the same node however deep they are.
+/// Nodes are numbered in post-order: every node comes after the nodes it refers to.
+#[derive(Default)]
+pub(crate) struct Interner {
+    nodes: Vec<TokenStream>,
+    /// The name of each node that is always bound to a `let` of its own, see [`Interner::bind`]
+    fixed: Vec<Option<Ident>>,
+    /// The node of each type name and token string
+    ids: HashMap<(&'static str, String), usize>,
+}
+
+impl Interner {
+    /// The placeholder standing for the node of `expr`, the tokens of a value of type `type_name`
+    /// whose nested values are placeholders.
+    pub(crate) fn intern(&mut self, type_name: &'static str, expr: TokenStream) -> Ident {
+        let (nodes, fixed) = (&mut self.nodes, &mut self.fixed);
+        let id = *self
+            .ids
+            .entry((type_name, expr.to_string()))
+            .or_insert_with(|| {
+                nodes.push(expr);
+                fixed.push(None);
+                nodes.len() - 1
+            });
+        Ident::new(&format!("{PLACEHOLDER_PREFIX}{id}"), Span::call_site())
+    }
+
+    /// Bind `expr` to `let name`, in order with the nodes found so far.
+    /// The output refers to it by name, e.g. the allocation of an `Rc` shared by several values.
+    pub(crate) fn bind(&mut self, name: Ident, expr: TokenStream) {
+        self.nodes.push(expr);
+        self.fixed.push(Some(name));
+    }
+
+    /// Replace the placeholders in `root`, binding the subtrees worth sharing (and those nested too deep) to `let`s.
+    ///
+    /// Subtrees that refer to one of the `scoped` names, which only exist inside an `Rc::new_cyclic` closure,
+    /// are always inlined.
+    pub(crate) fn render(
+        self,
+        root: TokenStream,
+        nesting: Nesting,
+        scoped: &HashSet<String>,
+    ) -> (TokenStream, DedupReport) {
+        let Interner { nodes, fixed, .. } = self;
+        let (root_size, root_refs) = scan(&root);
+
+        // Bottom-up: the size of each node with all its subtrees inlined, and whether it refers to a scoped name
+        let mut refs = Vec::with_capacity(nodes.len());
+        let mut full_size = Vec::with_capacity(nodes.len());
+        let mut pinned = Vec::with_capacity(nodes.len());
+        for expr in &nodes {
+            let (size, node_refs) = scan(expr);
+            let size = node_refs
+                .iter()
+                .fold(size, |size, &child| size.saturating_add(full_size[child]));
+            full_size.push(size);
+            pinned.push(
+                !scoped.is_empty()
+                    && (node_refs.iter().any(|&child| pinned[child]) || mentions(expr, scoped)),
+            );
+            refs.push(node_refs);
+        }
+        let tokens_before = root_refs.iter().fold(root_size, |size, &child| {
+            size.saturating_add(full_size[child])
+        });
+
+        // Top-down: how many times each node occurs in the output, how deep, and whether it is bound to a `let`
+        let mut uses = vec![0usize; nodes.len()];
+        let mut depth = vec![0usize; nodes.len()];
+        let mut bound = vec![false; nodes.len()];
+        for &child in &root_refs {
+            uses[child] += 1;
+        }
+        for id in (0..nodes.len()).rev() {
+            if fixed[id].is_some() {
+                uses[id] = uses[id].max(1);
+            }
+            if uses[id] == 0 {
+                // Only referred to by a subtree that turned out to be a duplicate
+                continue;
+            }
+            let shared = uses[id] >= 2 && worth_sharing(full_size[id], uses[id]);
+            let too_deep = match nesting {
+                Nesting::Nested => false,
+                Nesting::Flatten { max_depth } => depth[id] >= max_depth.max(1),
+            };
+            bound[id] = fixed[id].is_some() || (!pinned[id] && (shared || too_deep));
+
+            let (child_uses, child_depth) = match bound[id] {
+                true => (1, 1),
+                false => (uses[id], depth[id] + 1),
+            };
+            for &child in &refs[id] {
+                uses[child] = uses[child].saturating_add(child_uses);
+                depth[child] = depth[child].max(child_depth);
+            }
+        }
+
+        // Bottom-up again: the tokens of each node, and the `let`s of the bound ones
+        let mut rendered: Vec<Option<TokenStream>> = vec![None; nodes.len()];
+        let mut names: Vec<Option<Ident>> = vec![None; nodes.len()];
+        let mut bindings = TokenStream::new();
+        let mut next_binding = 0;
+        let replace = |id: usize,
+                       out: &mut TokenStream,
+                       rendered: &[Option<TokenStream>],
+                       names: &[Option<Ident>]| {
+            match &names[id] {
+                Some(name) if uses[id] >= 2 => out.extend(quote! {#name.clone()}),
+                Some(name) => out.append(name.clone()),
+                None => out.extend(
+                    rendered[id]
+                        .clone()
+                        .expect("nodes are rendered in post-order"),
+                ),
+            }
+        };
+        for (id, expr) in nodes.into_iter().enumerate() {
+            if uses[id] == 0 {
+                continue;
+            }
+            let expr = substitute(expr, |child, out| replace(child, out, &rendered, &names));
+            if let Some(name) = &fixed[id] {
+                bindings.extend(quote! {let #name = #expr;});
+            } else if bound[id] {
+                let name = Ident::new(&format!("__q_{next_binding}"), Span::call_site());
+                bindings.extend(quote! {let #name = #expr;});
+                names[id] = Some(name);
+                next_binding += 1;
+            } else {
+                rendered[id] = Some(expr);
+            }
+        }
+        let root = substitute(root, |child, out| replace(child, out, &rendered, &names));
+
+        let output = if bindings.is_empty() {
+            root
+        } else {
+            bindings.extend(root);
+            TokenStream::from(TokenTree::Group(Group::new(Delimiter::Brace, bindings)))
+        };
+        let report = DedupReport {
+            tokens_before,
+            tokens_after: count_tokens(&output),
+            shared: (0..bound.len())
+                .filter(|&id| bound[id] && uses[id] >= 2 && fixed[id].is_none())
+                .count(),
+        };
+        (output, report)
+    }
+}
+
+/// Whether `let __q_N = <size tokens>;` plus `__q_N.clone()` at every use is shorter than `uses` copies.
+fn worth_sharing(size: usize, uses: usize) -> bool {
+    const LET_OVERHEAD: usize = 4; // `let`, name, `=`, `;`
+    const CLONE_OVERHEAD: usize = 4; // name, `.`, `clone`, `()`
+    size.saturating_mul(uses - 1) > LET_OVERHEAD + CLONE_OVERHEAD * uses
+}
+
+fn placeholder_id(ident: &Ident) -> Option<usize> {
+    ident
+        .to_string()
+        .strip_prefix(PLACEHOLDER_PREFIX)?
+        .parse()
+        .ok()
+}
+
+/// The number of token trees in `tokens` that are not placeholders, and the placeholders in order.
+fn scan(tokens: &TokenStream) -> (usize, Vec<usize>) {
+    let mut size = 0;
+    let mut refs = Vec::new();
+    // Explicit stack, so that deeply nested tokens cannot overflow the call stack
+    let mut stack = vec![tokens.clone().into_iter()];
+    while let Some(iter) = stack.last_mut() {
+        match iter.next() {
+            Some(TokenTree::Ident(ident)) => match placeholder_id(&ident) {
+                Some(id) => refs.push(id),
+                None => size += 1,
+            },
+            Some(TokenTree::Group(group)) => {
+                size += 1;
+                stack.push(group.stream().into_iter());
+            }
+            Some(_) => size += 1,
+            None => {
+                stack.pop();
+            }
+        }
+    }
+    (size, refs)
+}
+
+/// Whether `tokens` contain one of `names`, outside of placeholders.
+fn mentions(tokens: &TokenStream, names: &HashSet<String>) -> bool {
+    let mut stack = vec![tokens.clone().into_iter()];
+    while let Some(iter) = stack.last_mut() {
+        match iter.next() {
+            Some(TokenTree::Ident(ident)) if names.contains(&ident.to_string()) => return true,
+            Some(TokenTree::Group(group)) => stack.push(group.stream().into_iter()),
+            Some(_) => {}
+            None => {
+                stack.pop();
+            }
+        }
+    }
+    false
+}
+
+pub(crate) fn count_tokens(tokens: &TokenStream) -> usize {
+    scan(tokens).0
+}
+
+/// Rebuild `tokens`, writing each placeholder with `f`.
+fn substitute(tokens: TokenStream, mut f: impl FnMut(usize, &mut TokenStream)) -> TokenStream {
+    let mut stack: Vec<(Option<Group>, TokenStream, token_stream::IntoIter)> =
+        vec![(None, TokenStream::new(), tokens.into_iter())];
+    loop {
+        let (_, out, iter) = stack.last_mut().expect("stack is never empty");
+        match iter.next() {
+            Some(TokenTree::Ident(ident)) => match placeholder_id(&ident) {
+                Some(id) => f(id, out),
+                None => out.append(ident),
+            },
+            Some(TokenTree::Group(group)) => {
+                let inner = group.stream().into_iter();
+                stack.push((Some(group), TokenStream::new(), inner));
+            }
+            Some(token) => out.append(token),
+            None => {
+                let (group, out, _) = stack.pop().expect("stack is never empty");
+                let Some(group) = group else {
+                    return out;
+                };
+                let mut rebuilt = Group::new(group.delimiter(), out);
+                rebuilt.set_span(group.span());
+                let (_, parent, _) = stack.last_mut().expect("groups always have a parent");
+                parent.append(rebuilt);
+            }
+        }
+    }
+}
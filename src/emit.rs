@@ -0,0 +1,757 @@
+use std::collections::{HashMap, HashSet};
+use std::mem;
+
+use proc_macro2::{Delimiter, Group, Ident, Span, TokenStream, TokenTree};
+use quote::{TokenStreamExt, quote};
+
+use crate::dedup::{DedupReport, Interner, count_tokens};
+use crate::tokens::append_comma;
+use crate::{Cycles, Dedup, Nesting, PathSegment, Quine, QuineContext, QuineError, QuineErrorKind};
+
+/// Emits the constructor tokens of a value with an explicit work stack instead of recursion,
+/// so that deeply nested values (e.g. a linked list of a million `Option<Box<Node>>`s) cannot overflow the call stack.
+///
+/// A value describes its tokens to the emitter in [`Quine::emit`], in output order:
+/// tokens and delimiters it knows up front, and the nested values in between.
+/// Nested values are not visited until `emit` returns; their tokens end up where they were scheduled.
+///
+/// ```
+/// use polyquine::{Emitter, PathSegment, Quine, QuineContext, QuineError};
+/// use proc_macro2::{Delimiter, TokenStream};
+///
+/// struct Wrapper(Vec<i32>);
+///
+/// impl Quine for Wrapper {
+///     fn try_to_ctor_tokens_with(&self, ctx: &QuineContext, out: &mut TokenStream) -> Result<(), QuineError> {
+///         Emitter::run(self, ctx, out)
+///     }
+///
+///     fn emit<'a>(&'a self, e: &mut Emitter<'a>) -> Result<(), QuineError> {
+///         e.append(|out| out.extend(quote::quote!(Wrapper)));
+///         e.open(Delimiter::Parenthesis);
+///         e.value_at(PathSegment::TupleField(0), &self.0);
+///         e.close();
+///         Ok(())
+///     }
+/// }
+///
+/// assert_eq!(Wrapper(vec![1]).ctor_tokens().to_string(), "Wrapper (:: std :: vec :: Vec :: from ([1i32]))");
+/// ```
+pub struct Emitter<'a> {
+    ctx: &'a QuineContext,
+    /// Work still to do, the next task last
+    tasks: Vec<Task<'a>>,
+    /// Tasks scheduled by the value being emitted, in output order.
+    /// They are moved to `tasks` once its `emit` returns.
+    pending: Vec<Task<'a>>,
+    /// How many segments of `state.path` belong to the emitter that called [`Emitter::borrowed`]
+    outer_path: usize,
+    state: State,
+}
+
+/// Everything but the tasks, which an emitter of borrowed values takes over, see [`Emitter::borrowed`].
+#[derive(Default)]
+struct State {
+    /// The output stream, followed by the contents of every group still open
+    groups: Vec<(Delimiter, TokenStream)>,
+    /// Where in the root value the task being run is, outermost first
+    path: Vec<PathSegment>,
+    /// How deep the value being emitted is nested in the expression being built
+    depth: usize,
+    /// Where `let` statements go: the output block, then the body of each allocation in `open`
+    scopes: Vec<Scope>,
+    next_scope: usize,
+    /// The expressions being built for a `let`, innermost last
+    frames: Vec<Frame>,
+    next_binding: usize,
+    /// The distinct subtrees found so far, with [`Dedup::Subtrees`]
+    interner: Option<Interner>,
+    /// Each shared allocation found so far, by address
+    shared: HashMap<*const (), Allocation>,
+    /// The shared allocations being built, outermost first
+    open: Vec<OpenAllocation>,
+    /// Names that only exist inside an `Rc::new_cyclic` closure, which deduplicated subtrees must not be moved out of
+    scoped: HashSet<String>,
+    /// Whether the output calls something that cannot be evaluated in a `const` context, see [`Emitter::non_const`]
+    non_const: bool,
+    /// Whether each derived type the task being run is nested in emits its fields in const form, innermost last,
+    /// see [`Emitter::enter_const`]
+    const_form: Vec<bool>,
+}
+
+/// What [`Emitter::emit_into`] found out about the output besides its tokens.
+struct Emitted {
+    report: Option<DedupReport>,
+    const_eval: bool,
+}
+
+/// `let` statements binding temporaries that the output refers to, see [`Nesting::Flatten`] and [`Emitter::shared`]
+struct Scope {
+    id: usize,
+    bindings: TokenStream,
+}
+
+struct Frame {
+    /// The innermost scope this expression refers to, which is where its `let` goes
+    needs: usize,
+    /// The scope of the allocation being built, whose own names do not count
+    own: usize,
+}
+
+struct Allocation {
+    name: Ident,
+    /// The level and id of the scope of its `let`, once it is built
+    scope: Option<(usize, usize)>,
+}
+
+enum Found {
+    /// Bound to a name in the scope at this level
+    Bound(Ident, usize),
+    /// Still being built, so this is a cycle
+    Building,
+    /// Bound inside an `Rc::new_cyclic` closure that is closed by now
+    OutOfScope,
+}
+
+struct OpenAllocation {
+    ptr: *const (),
+    path: TokenStream,
+    /// `path::new(..)` around its value; otherwise its tokens are the whole constructor
+    sized: bool,
+    /// The parameter of its `new_cyclic` closure, once a `Weak` points back to it
+    weak: Option<Ident>,
+}
+
+enum Task<'a> {
+    /// A value, and how deep it is nested in the expression being built
+    Value(&'a dyn Quine, usize),
+    /// The end of the expression bound to a temporary `__q_N`
+    Bind(Ident),
+    /// The end of the value of the allocation bound to `__rc_N`
+    EndShared(Ident),
+    /// The end of the tokens of a value of this type, which are replaced by a placeholder for their node, see [`Interner`]
+    Intern(&'static str),
+    Tokens(TokenStream),
+    Open(Delimiter),
+    Close,
+    Enter(PathSegment),
+    Leave,
+    EnterConst(bool),
+    LeaveConst,
+}
+
+/// An item of the iterator passed to [`Emitter::items`].
+pub trait EmitItem<'a> {
+    /// Append the constructor tokens of the item, at the location already entered for it.
+    fn emit_item(self, e: &mut Emitter<'a>);
+}
+
+/// A value, emitted as itself.
+impl<'a, T: Quine + 'a> EmitItem<'a> for &'a T {
+    fn emit_item(self, e: &mut Emitter<'a>) {
+        e.value(self);
+    }
+}
+
+/// The entry of a map, emitted as a `(key, value)` tuple.
+impl<'a, K: Quine + 'a, V: Quine + 'a> EmitItem<'a> for (&'a K, &'a V) {
+    fn emit_item(self, e: &mut Emitter<'a>) {
+        let (key, value) = self;
+        e.open(Delimiter::Parenthesis);
+        e.value_at(PathSegment::TupleField(0), key);
+        e.append(append_comma);
+        e.value_at(PathSegment::TupleField(1), value);
+        e.close();
+    }
+}
+
+impl<'a> Emitter<'a> {
+    /// Append the constructor tokens of `value` to `out`.
+    ///
+    /// This is how types that override [`Quine::emit`] implement [`Quine::try_to_ctor_tokens_with`].
+    /// They must not do so without overriding `emit`, which by default calls `try_to_ctor_tokens_with`.
+    pub fn run(
+        value: &'a dyn Quine,
+        ctx: &'a QuineContext,
+        out: &mut TokenStream,
+    ) -> Result<(), QuineError> {
+        Self::emit_into(value, ctx, out).map(|_| ())
+    }
+
+    /// [`Emitter::run`], also telling whether the output can be evaluated in a `const` context,
+    /// e.g. as the value of a `const` or `static` item.
+    ///
+    /// This follows the shape of the value: it is false if any part of it was emitted through [`Emitter::non_const`],
+    /// which the implementations of allocating types such as `String` or `Vec` call, and `.clone()`s from [`Dedup::Subtrees`].
+    pub fn run_const_eval(
+        value: &'a dyn Quine,
+        ctx: &'a QuineContext,
+        out: &mut TokenStream,
+    ) -> Result<bool, QuineError> {
+        Self::emit_into(value, ctx, out).map(|emitted| emitted.const_eval)
+    }
+
+    /// [`Emitter::run`], also reporting how much [`Dedup::Subtrees`] reduced the size of the output.
+    /// Without it, the report shows no reduction.
+    pub fn run_with_report(
+        value: &'a dyn Quine,
+        ctx: &'a QuineContext,
+        out: &mut TokenStream,
+    ) -> Result<DedupReport, QuineError> {
+        let mut tokens = TokenStream::new();
+        let report = Self::emit_into(value, ctx, &mut tokens)?
+            .report
+            .unwrap_or_else(|| {
+                let size = count_tokens(&tokens);
+                DedupReport {
+                    tokens_before: size,
+                    tokens_after: size,
+                    shared: 0,
+                }
+            });
+        out.extend(tokens);
+        Ok(report)
+    }
+
+    fn emit_into(
+        value: &'a dyn Quine,
+        ctx: &'a QuineContext,
+        out: &mut TokenStream,
+    ) -> Result<Emitted, QuineError> {
+        let mut emitter = Emitter {
+            ctx,
+            tasks: vec![Task::Value(value, 0)],
+            pending: Vec::new(),
+            outer_path: 0,
+            state: State {
+                // The delimiter of the output stream itself is never used
+                groups: vec![(Delimiter::None, TokenStream::new())],
+                scopes: vec![Scope {
+                    id: 0,
+                    bindings: TokenStream::new(),
+                }],
+                next_scope: 1,
+                interner: match ctx.dedup() {
+                    Dedup::Off => None,
+                    Dedup::Subtrees => Some(Interner::default()),
+                },
+                ..State::default()
+            },
+        };
+        let result = emitter.drain();
+        let mut state = emitter.state;
+        if result.is_ok() {
+            assert_eq!(
+                state.groups.len(),
+                1,
+                "Emitter::open() without a matching close()"
+            );
+        }
+
+        // On error, `out` keeps the tokens of the groups that were closed
+        state.groups.truncate(1);
+        let (_, emitted) = state
+            .groups
+            .pop()
+            .expect("the output stream is never closed");
+        if let Some(interner) = state.interner {
+            // Partial output would be full of placeholders
+            result?;
+            let (emitted, report) = interner.render(emitted, ctx.nesting(), &state.scoped);
+            out.extend(emitted);
+            return Ok(Emitted {
+                const_eval: !state.non_const && report.shared == 0,
+                report: Some(report),
+            });
+        }
+        state.scopes.truncate(1);
+        let Scope { bindings, .. } = state
+            .scopes
+            .pop()
+            .expect("the output block is never closed");
+        if bindings.is_empty() {
+            out.extend(emitted);
+        } else {
+            let mut block = bindings;
+            block.extend(emitted);
+            out.append(Group::new(Delimiter::Brace, block));
+        }
+        result.map(|_| Emitted {
+            report: None,
+            const_eval: !state.non_const,
+        })
+    }
+
+    /// Whether a value nested `depth` levels deep is bound to a temporary instead of being emitted in place.
+    /// With [`Dedup::Subtrees`], this is decided once all subtrees are known.
+    fn binds(&self, depth: usize) -> bool {
+        if self.state.interner.is_some() {
+            return false;
+        }
+        match self.ctx.nesting() {
+            Nesting::Nested => false,
+            Nesting::Flatten { max_depth } => depth >= max_depth.max(1),
+        }
+    }
+
+    fn drain(&mut self) -> Result<(), QuineError> {
+        while let Some(task) = self.tasks.pop() {
+            match task {
+                Task::Value(value, depth) if self.binds(depth) => {
+                    // Emit `__q_N` in place, and the value itself as the start of a new expression
+                    let binding = binding_ident(self.state.next_binding);
+                    self.state.next_binding += 1;
+                    self.out().append(binding.clone());
+                    self.start_frame(usize::MAX);
+                    self.tasks.push(Task::Bind(binding));
+                    self.tasks.push(Task::Value(value, 0));
+                }
+                Task::Value(value, depth) => {
+                    self.state.depth = depth;
+                    if self.state.interner.is_some() {
+                        self.tasks.push(Task::Intern(value.quine_type_name()));
+                        self.state
+                            .groups
+                            .push((Delimiter::None, TokenStream::new()));
+                    }
+                    if let Err(err) = value.emit(self) {
+                        return Err(self.locate(err));
+                    }
+                    self.tasks.extend(self.pending.drain(..).rev());
+                }
+                Task::Intern(type_name) => {
+                    let (_, expr) = self
+                        .state
+                        .groups
+                        .pop()
+                        .expect("opened when the value started");
+                    let interner = self
+                        .state
+                        .interner
+                        .as_mut()
+                        .expect("only scheduled with an interner");
+                    let placeholder = interner.intern(type_name, expr);
+                    self.out().append(placeholder);
+                }
+                Task::Bind(binding) => {
+                    let (expr, level) = self.end_frame();
+                    self.bind(level, binding, expr);
+                }
+                Task::EndShared(name) => self.end_shared(name),
+                Task::Tokens(tokens) => self.out().extend(tokens),
+                Task::Open(delimiter) => self.state.groups.push((delimiter, TokenStream::new())),
+                Task::Close => self.close_group(),
+                Task::Enter(segment) => self.state.path.push(segment),
+                Task::Leave => {
+                    self.state.path.pop();
+                }
+                Task::EnterConst(konst) => self.state.const_form.push(konst),
+                Task::LeaveConst => {
+                    self.state.const_form.pop();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Add the location of the task being run to an error raised by it.
+    fn locate(&self, err: QuineError) -> QuineError {
+        self.state.path[self.outer_path..]
+            .iter()
+            .rev()
+            .fold(err, |err, segment| err.with_segment(segment.clone()))
+    }
+
+    fn out(&mut self) -> &mut TokenStream {
+        let (_, out) = self
+            .state
+            .groups
+            .last_mut()
+            .expect("the output stream is never closed");
+        out
+    }
+
+    fn close_group(&mut self) {
+        assert!(
+            self.state.groups.len() > 1,
+            "Emitter::close() without a matching open()"
+        );
+        let (delimiter, inner) = self.state.groups.pop().expect("checked above");
+        self.out().append(Group::new(delimiter, inner));
+    }
+
+    /// Start building the expression of a `let`, not counting references to the scope `own` and those inside it.
+    fn start_frame(&mut self, own: usize) {
+        self.state
+            .groups
+            .push((Delimiter::None, TokenStream::new()));
+        self.state.frames.push(Frame { needs: 0, own });
+    }
+
+    /// The expression of a `let`, and the scope it must go in.
+    fn end_frame(&mut self) -> (TokenStream, usize) {
+        let (_, expr) = self.state.groups.pop().expect("opened with the frame");
+        let frame = self
+            .state
+            .frames
+            .pop()
+            .expect("started with the expression");
+        (expr, frame.needs)
+    }
+
+    /// Record that the expression being built refers to a name bound in the scope `level`.
+    fn refer(&mut self, level: usize) {
+        if let Some(frame) = self.state.frames.last_mut()
+            && level < frame.own
+        {
+            frame.needs = frame.needs.max(level);
+        }
+    }
+
+    /// Add `let name = expr;` to the scope `level`, and refer to it from the enclosing expression.
+    fn bind(&mut self, level: usize, name: Ident, expr: TokenStream) {
+        match &mut self.state.interner {
+            Some(interner) if level == 0 => interner.bind(name, expr),
+            _ => {
+                if level > 0 {
+                    self.state.scoped.insert(name.to_string());
+                }
+                let scope = &mut self.state.scopes[level].bindings;
+                scope.extend(quote! {let #name = #expr;});
+            }
+        }
+        self.refer(level);
+    }
+
+    /// Bind the allocation whose value was just emitted to `name`.
+    fn end_shared(&mut self, name: Ident) {
+        let (value, level) = self.end_frame();
+        let allocation = self.state.open.pop().expect("opened with the allocation");
+        let scope = self.state.scopes.pop().expect("opened with the allocation");
+        let value = if scope.bindings.is_empty() {
+            value
+        } else {
+            let mut block = scope.bindings;
+            block.extend(value);
+            TokenStream::from(TokenTree::Group(Group::new(Delimiter::Brace, block)))
+        };
+        let path = allocation.path;
+        let expr = match (allocation.sized, allocation.weak) {
+            (true, Some(weak)) => quote! {#path::new_cyclic(|#weak| #value)},
+            (true, None) => quote! {#path::new(#value)},
+            (false, _) => value,
+        };
+        let scope_id = self.state.scopes[level].id;
+        self.state
+            .shared
+            .get_mut(&allocation.ptr)
+            .expect("registered when opened")
+            .scope = Some((level, scope_id));
+        self.bind(level, name, expr);
+    }
+
+    /// Tasks can run straight away until the value being emitted schedules a nested value;
+    /// everything after it has to wait until it has been emitted.
+    fn schedule(&mut self, task: Task<'a>) {
+        if !self.pending.is_empty() {
+            self.pending.push(task);
+            return;
+        }
+        match task {
+            Task::Value(..) | Task::Bind(_) | Task::EndShared(_) | Task::Intern(_) => {
+                self.pending.push(task)
+            }
+            Task::Tokens(tokens) => self.out().extend(tokens),
+            Task::Open(delimiter) => self.state.groups.push((delimiter, TokenStream::new())),
+            Task::Close => self.close_group(),
+            Task::Enter(segment) => self.state.path.push(segment),
+            Task::Leave => {
+                self.state.path.pop();
+            }
+            Task::EnterConst(konst) => self.state.const_form.push(konst),
+            Task::LeaveConst => {
+                self.state.const_form.pop();
+            }
+        }
+    }
+
+    /// What became of the shared allocation at `ptr`, if it was found before.
+    fn find_shared(&self, ptr: *const ()) -> Option<Found> {
+        let allocation = self.state.shared.get(&ptr)?;
+        Some(match allocation.scope {
+            None => Found::Building,
+            Some((level, id)) if self.state.scopes.get(level).is_some_and(|s| s.id == id) => {
+                Found::Bound(allocation.name.clone(), level)
+            }
+            Some(_) => Found::OutOfScope,
+        })
+    }
+
+    pub fn ctx(&self) -> &'a QuineContext {
+        self.ctx
+    }
+
+    /// Append the tokens written by `f`.
+    pub fn append(&mut self, f: impl FnOnce(&mut TokenStream)) {
+        if self.pending.is_empty() {
+            f(self.out());
+        } else {
+            let mut tokens = TokenStream::new();
+            f(&mut tokens);
+            self.pending.push(Task::Tokens(tokens));
+        }
+    }
+
+    /// Append the tokens written by `f`, which may fail.
+    pub fn try_append(
+        &mut self,
+        f: impl FnOnce(&mut TokenStream) -> Result<(), QuineError>,
+    ) -> Result<(), QuineError> {
+        if self.pending.is_empty() {
+            f(self.out())
+        } else {
+            let mut tokens = TokenStream::new();
+            f(&mut tokens)?;
+            self.pending.push(Task::Tokens(tokens));
+            Ok(())
+        }
+    }
+
+    /// Mark the output as not evaluable in a `const` context, see [`Emitter::run_const_eval`]:
+    /// the value being emitted, of type `type_name`, calls a function that is not `const`, e.g. `String::from` or `Vec::from`.
+    ///
+    /// Fails with [`QuineErrorKind::NotConst`] within a type emitted in const form, see [`Emitter::enter_const`].
+    pub fn non_const(&mut self, type_name: &'static str) -> Result<(), QuineError> {
+        if self.is_const() {
+            return Err(QuineError::not_const(type_name));
+        }
+        self.state.non_const = true;
+        Ok(())
+    }
+
+    /// Emit the values up to the matching [`Emitter::leave_const`] in const form if `konst`, otherwise in the usual form.
+    /// `#[derive(Quine)]` does this around the fields of every type, with `konst` set by `#[polyquine(const)]`,
+    /// so a derived type nested in another one keeps its own form.
+    ///
+    /// In const form, `String`s are emitted as `&'static str` literals, sequences and maps as `&[..]` slices,
+    /// and `Box<T>` as `&T`. Values that have no const form fail with [`QuineErrorKind::NotConst`].
+    pub fn enter_const(&mut self, konst: bool) {
+        self.schedule(Task::EnterConst(konst));
+    }
+
+    /// Return to the form of the values before the matching [`Emitter::enter_const`].
+    pub fn leave_const(&mut self) {
+        self.schedule(Task::LeaveConst);
+    }
+
+    /// Whether the value being emitted is in const form, see [`Emitter::enter_const`].
+    pub fn is_const(&self) -> bool {
+        self.state.const_form.last() == Some(&true)
+    }
+
+    /// Open a group delimited by `delimiter`; it contains everything up to the matching [`Emitter::close`].
+    pub fn open(&mut self, delimiter: Delimiter) {
+        self.schedule(Task::Open(delimiter));
+    }
+
+    pub fn close(&mut self) {
+        self.schedule(Task::Close);
+    }
+
+    /// Append the constructor tokens of `value`.
+    pub fn value(&mut self, value: &'a dyn Quine) {
+        self.schedule(Task::Value(value, self.state.depth + 1));
+    }
+
+    /// Append the constructor tokens of `value`, locating its errors at `segment`.
+    pub fn value_at(&mut self, segment: PathSegment, value: &'a dyn Quine) {
+        self.enter(segment);
+        self.value(value);
+        self.leave();
+    }
+
+    /// Append the constructor tokens of each item, separated by commas and located at their indices.
+    /// The items are references to values, or the `(&key, &value)` entries of a map (see [`EmitItem`]).
+    pub fn items<I: EmitItem<'a>>(&mut self, items: impl IntoIterator<Item = I>) {
+        for (i, item) in items.into_iter().enumerate() {
+            if i > 0 {
+                self.append(append_comma);
+            }
+            self.enter(PathSegment::Index(i));
+            item.emit_item(self);
+            self.leave();
+        }
+    }
+
+    /// Append a `(key, value)` tuple for each entry of a map, separated by commas and located at their indices.
+    pub fn entries<K: Quine + 'a, V: Quine + 'a>(
+        &mut self,
+        entries: impl IntoIterator<Item = (&'a K, &'a V)>,
+    ) {
+        self.items(entries);
+    }
+
+    /// Append the constructor tokens of a value that only lives until `emit` returns,
+    /// such as the contents of a `RefCell` behind the guard of its borrow.
+    ///
+    /// The value is emitted before this returns, with a call on the call stack for each borrowed value it contains,
+    /// so it must come before the nested values scheduled by the same `emit`.
+    pub fn borrowed(&mut self, value: &dyn Quine) -> Result<(), QuineError> {
+        assert!(
+            self.pending.is_empty(),
+            "Emitter::borrowed() after a nested value"
+        );
+        let depth = self.state.depth;
+        let outer_path = self.state.path.len();
+        let mut emitter = Emitter {
+            ctx: self.ctx,
+            tasks: vec![Task::Value(value, depth + 1)],
+            pending: Vec::new(),
+            outer_path,
+            state: mem::take(&mut self.state),
+        };
+        let result = emitter.drain();
+        self.state = emitter.state;
+        self.state.depth = depth;
+        // The error is located already
+        self.state.path.truncate(outer_path);
+        result
+    }
+
+    /// Append the value of the reference-counted allocation at `ptr`, whose pointer type is at `path`,
+    /// so that the output shares allocations the same way the value does.
+    /// If the allocation is `sized`, `new` emits its value and this wraps it in `path::new(..)`;
+    /// otherwise `new` emits the whole constructor.
+    ///
+    /// Unless the allocation is `unique`, it is bound to `let __rc_N = ..;` the first time it is found,
+    /// and every use of it, that one included, is `path::clone(&__rc_N)`.
+    pub(crate) fn shared(
+        &mut self,
+        ptr: *const (),
+        unique: bool,
+        path: TokenStream,
+        sized: bool,
+        new: impl FnOnce(&mut Self),
+    ) -> Result<(), QuineError> {
+        if unique {
+            if sized {
+                self.append(|out| out.extend(quote! {#path::new}));
+                self.open(Delimiter::Parenthesis);
+                new(self);
+                self.close();
+            } else {
+                new(self);
+            }
+            return Ok(());
+        }
+        match self.find_shared(ptr) {
+            Some(Found::Bound(name, level)) => {
+                self.append(|out| out.extend(quote! {#path::clone(&#name)}));
+                self.refer(level);
+                return Ok(());
+            }
+            Some(Found::Building) => return Err(QuineError::cycle(false)),
+            Some(Found::OutOfScope) => {
+                return Err(QuineError::new(QuineErrorKind::EscapedAllocation));
+            }
+            None => {}
+        }
+
+        debug_assert!(self.pending.is_empty(), "the allocation starts its value");
+        let name = Ident::new(
+            &format!("__rc_{}", self.state.shared.len()),
+            Span::call_site(),
+        );
+        self.append(|out| out.extend(quote! {#path::clone(&#name)}));
+        self.state.shared.insert(
+            ptr,
+            Allocation {
+                name: name.clone(),
+                scope: None,
+            },
+        );
+        self.state.open.push(OpenAllocation {
+            ptr,
+            path,
+            sized,
+            weak: None,
+        });
+        let level = self.state.scopes.len();
+        self.state.scopes.push(Scope {
+            id: self.state.next_scope,
+            bindings: TokenStream::new(),
+        });
+        self.state.next_scope += 1;
+        self.start_frame(level);
+        new(self);
+        self.schedule(Task::EndShared(name));
+        Ok(())
+    }
+
+    /// Append a weak pointer of type `weak_path` to the allocation at `ptr`, if it is still alive,
+    /// whose strong pointer type is at `path`.
+    ///
+    /// The allocation must have been found earlier, as `path::downgrade(&__rc_N)` refers to its `let`.
+    /// If it is still being built, this is a cycle, see [`Cycles`].
+    pub(crate) fn weak(
+        &mut self,
+        ptr: Option<*const ()>,
+        path: TokenStream,
+        weak_path: TokenStream,
+    ) -> Result<(), QuineError> {
+        let Some(ptr) = ptr else {
+            self.append(|out| out.extend(quote! {#weak_path::new()}));
+            return Ok(());
+        };
+        match self.find_shared(ptr) {
+            None => Err(QuineError::new(QuineErrorKind::UnownedWeak)),
+            Some(Found::OutOfScope) => Err(QuineError::new(QuineErrorKind::EscapedAllocation)),
+            Some(Found::Bound(name, level)) => {
+                self.append(|out| out.extend(quote! {#path::downgrade(&#name)}));
+                self.refer(level);
+                Ok(())
+            }
+            Some(Found::Building) => {
+                if self.ctx.cycles() == Cycles::Error {
+                    return Err(QuineError::cycle(true));
+                }
+                let index = self
+                    .state
+                    .open
+                    .iter()
+                    .rposition(|open| open.ptr == ptr)
+                    .expect("allocations without a scope are being built");
+                let open = &mut self.state.open[index];
+                if !open.sized {
+                    return Err(QuineError::cycle(true));
+                }
+                let name = &self.state.shared[&ptr].name;
+                let weak = open
+                    .weak
+                    .get_or_insert_with(|| {
+                        let weak = name.to_string().replacen("__rc_", "__weak_", 1);
+                        Ident::new(&weak, Span::call_site())
+                    })
+                    .clone();
+                self.state.scoped.insert(weak.to_string());
+                self.append(|out| out.extend(quote! {#weak_path::clone(#weak)}));
+                // The closure parameter is in the scope of the allocation's body
+                self.refer(index + 1);
+                Ok(())
+            }
+        }
+    }
+
+    /// Locate the errors of everything up to the matching [`Emitter::leave`] inside `segment`.
+    pub fn enter(&mut self, segment: PathSegment) {
+        self.schedule(Task::Enter(segment));
+    }
+
+    pub fn leave(&mut self) {
+        self.schedule(Task::Leave);
+    }
+}
+
+fn binding_ident(binding: usize) -> Ident {
+    Ident::new(&format!("__q_{binding}"), Span::call_site())
+}
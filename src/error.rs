@@ -0,0 +1,213 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+
+/// An error raised while generating the constructor tokens of a value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuineError {
+    kind: QuineErrorKind,
+    path: FieldPath,
+}
+
+/// The reason why a value could not be turned into constructor tokens.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum QuineErrorKind {
+    /// The value is an enum variant marked with `#[polyquine(skip)]`.
+    SkippedVariant {
+        ty: &'static str,
+        variant: &'static str,
+    },
+    /// The value is a NaN or infinite float, which has no literal representation.
+    NonFiniteFloat(String),
+    /// The value refers back to an `Rc` or `Arc` that contains it.
+    /// Through a `Weak`, this can be rebuilt with [`Cycles::NewCyclic`](crate::Cycles::NewCyclic).
+    Cycle { weak: bool },
+    /// A `Weak` points to an allocation that is not owned by the value before it, so it has nothing to point to.
+    UnownedWeak,
+    /// An allocation built inside the `Rc::new_cyclic` closure of another one is also used after it.
+    EscapedAllocation,
+    /// The type cannot be written out, because its `Quine` implementation does not override
+    /// [`Quine::try_to_type_tokens_with`](crate::Quine::try_to_type_tokens_with).
+    UnnamedType(&'static str),
+    /// A value of this type has no const form, within a `#[polyquine(const)]` type
+    /// (see [`Emitter::enter_const`](crate::Emitter::enter_const)) or for [`quine_const`](crate::quine_const).
+    NotConst(&'static str),
+    /// The keys of an unordered collection cannot be sorted with [`MapOrder::Sorted`](crate::MapOrder::Sorted):
+    /// their type has no order, and a key cannot be emitted on its own, e.g. because it holds a `Weak`.
+    /// [`MapOrder::Iteration`](crate::MapOrder::Iteration) emits them unsorted.
+    UnsortableKey(&'static str),
+    /// A name that is not a valid identifier, e.g. a [`CratePath::Alias`](crate::CratePath::Alias) with a `-` in it.
+    InvalidIdent(String),
+    /// Any other error, e.g. one raised by a hand-written `Quine` implementation.
+    Custom(String),
+}
+
+impl QuineError {
+    pub fn new(kind: QuineErrorKind) -> Self {
+        Self {
+            kind,
+            path: FieldPath::default(),
+        }
+    }
+
+    pub fn skipped_variant(ty: &'static str, variant: &'static str) -> Self {
+        Self::new(QuineErrorKind::SkippedVariant { ty, variant })
+    }
+
+    pub fn non_finite_float(value: impl Display) -> Self {
+        Self::new(QuineErrorKind::NonFiniteFloat(value.to_string()))
+    }
+
+    pub fn cycle(weak: bool) -> Self {
+        Self::new(QuineErrorKind::Cycle { weak })
+    }
+
+    pub fn unnamed_type(type_name: &'static str) -> Self {
+        Self::new(QuineErrorKind::UnnamedType(type_name))
+    }
+
+    pub fn not_const(type_name: &'static str) -> Self {
+        Self::new(QuineErrorKind::NotConst(type_name))
+    }
+
+    pub fn unsortable_key(type_name: &'static str) -> Self {
+        Self::new(QuineErrorKind::UnsortableKey(type_name))
+    }
+
+    pub fn invalid_ident(name: impl Display) -> Self {
+        Self::new(QuineErrorKind::InvalidIdent(name.to_string()))
+    }
+
+    pub fn custom(message: impl Display) -> Self {
+        Self::new(QuineErrorKind::Custom(message.to_string()))
+    }
+
+    pub fn kind(&self) -> &QuineErrorKind {
+        &self.kind
+    }
+
+    /// Where in the value the error occurred.
+    pub fn path(&self) -> &FieldPath {
+        &self.path
+    }
+
+    /// Record that the error occurred inside `segment`.
+    /// Called by `Quine` implementations as the error bubbles up, so segments are added innermost first.
+    pub fn with_segment(mut self, segment: PathSegment) -> Self {
+        self.path.segments.push(segment);
+        self
+    }
+}
+
+/// One step on the way from the root value to the place where an error occurred.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PathSegment {
+    /// A value of the given struct type.
+    Type(&'static str),
+    /// A value of the given enum variant.
+    Variant {
+        ty: &'static str,
+        variant: &'static str,
+    },
+    /// A named field of a struct or enum variant.
+    Field(&'static str),
+    /// A positional field of a tuple, tuple struct or enum variant.
+    TupleField(usize),
+    /// An element of a sequence or collection.
+    Index(usize),
+}
+
+/// The path to a value inside the root value, e.g. `Ast::Sum.1[3].Mul.0.src`.
+/// Type names are only shown for the outermost value.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FieldPath {
+    // Innermost segment first
+    segments: Vec<PathSegment>,
+}
+
+impl FieldPath {
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    /// The segments of the path, outermost first.
+    pub fn segments(&self) -> impl DoubleEndedIterator<Item = &PathSegment> {
+        self.segments.iter().rev()
+    }
+}
+
+impl Display for FieldPath {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for (i, segment) in self.segments().enumerate() {
+            match segment {
+                PathSegment::Type(ty) if i == 0 => write!(f, "{ty}")?,
+                PathSegment::Type(_) => {}
+                PathSegment::Variant { ty, variant } if i == 0 => write!(f, "{ty}::{variant}")?,
+                PathSegment::Variant { variant, .. } => write!(f, ".{variant}")?,
+                PathSegment::Field(name) => write!(f, ".{name}")?,
+                PathSegment::TupleField(idx) => write!(f, ".{idx}")?,
+                PathSegment::Index(idx) => write!(f, "[{idx}]")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Display for QuineErrorKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QuineErrorKind::SkippedVariant { ty, variant } => write!(
+                f,
+                "Attempted to call ctor_tokens() on skipped enum variant {ty}::{variant}"
+            ),
+            QuineErrorKind::NonFiniteFloat(value) => {
+                write!(f, "Float value {value} has no literal representation")
+            }
+            QuineErrorKind::Cycle { weak: false } => write!(
+                f,
+                "Reference cycle through an `Rc` or `Arc` cannot be rebuilt"
+            ),
+            QuineErrorKind::Cycle { weak: true } => write!(
+                f,
+                "Reference cycle through a `Weak` can only be rebuilt with `Cycles::NewCyclic`"
+            ),
+            QuineErrorKind::UnownedWeak => write!(
+                f,
+                "`Weak` points to an allocation that is not owned by the value before it"
+            ),
+            QuineErrorKind::EscapedAllocation => write!(
+                f,
+                "Allocation built inside an `Rc::new_cyclic` closure is also used outside of it"
+            ),
+            QuineErrorKind::UnnamedType(type_name) => {
+                write!(
+                    f,
+                    "Type `{type_name}` cannot be written out as type arguments"
+                )
+            }
+            QuineErrorKind::NotConst(type_name) => {
+                write!(f, "Type `{type_name}` cannot be expressed as a constant")
+            }
+            QuineErrorKind::UnsortableKey(type_name) => write!(
+                f,
+                "Keys of type `{type_name}` cannot be sorted: they have no order and cannot be emitted on their own"
+            ),
+            QuineErrorKind::InvalidIdent(name) => {
+                write!(f, "`{name}` is not a valid identifier")
+            }
+            QuineErrorKind::Custom(message) => write!(f, "{message}"),
+        }
+    }
+}
+
+impl Display for QuineError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.kind)?;
+        if !self.path.is_empty() {
+            write!(f, " (at `{}`)", self.path)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for QuineError {}
@@ -0,0 +1,86 @@
+use proc_macro2::TokenStream;
+use quote::quote;
+
+use crate::context::StdItem;
+use crate::tokens::append_ident;
+use crate::{Emitter, QuineContext, QuineError, QuineType};
+
+// Items that bind a value to a name, for code generators that bake computed values into the code they write.
+// The value is emitted once, and where it can be evaluated in a `const` context
+// (see `Emitter::run_const_eval`) the item is a `const` holding it directly;
+// otherwise a `static` is built on first use by a `LazyLock`, and `try_quine_const_with` fails.
+
+/// Generate `const NAME: T = ..;` if `value` can be evaluated in a `const` context,
+/// otherwise `static NAME: LazyLock<T> = LazyLock::new(|| ..);`, shaped by `ctx`.
+/// Either way, `NAME` can be used as a `T`, through auto-deref for the `LazyLock`, which needs `T: Sync`.
+///
+/// Fails if the value or its type cannot be represented, see [`QuineType`].
+///
+/// # Panics
+/// If `name` is not a valid identifier.
+pub fn try_quine_static_with<T: QuineType>(
+    name: &str,
+    value: &T,
+    ctx: &QuineContext,
+) -> Result<TokenStream, QuineError> {
+    let (ident, ty, ctor, const_eval) = quine_item(name, value, ctx)?;
+    if const_eval {
+        return Ok(quote! {const #ident: #ty = #ctor;});
+    }
+    let mut lazy = TokenStream::new();
+    ctx.append_std_path(StdItem::LazyLock, &mut lazy);
+    Ok(quote! {static #ident: #lazy<#ty> = #lazy::new(|| #ctor);})
+}
+
+/// Infallible version of [`try_quine_static_with`] using the default [`QuineContext`].
+///
+/// # Panics
+/// If `try_quine_static_with` returns an error.
+pub fn quine_static<T: QuineType>(name: &str, value: &T) -> TokenStream {
+    try_quine_static_with(name, value, &QuineContext::default())
+        .unwrap_or_else(|err| panic!("{err}"))
+}
+
+/// Generate `const NAME: T = ..;`, shaped by `ctx`.
+///
+/// Fails with [`QuineErrorKind::NotConst`](crate::QuineErrorKind::NotConst) if `value` cannot be evaluated
+/// in a `const` context, e.g. because it allocates; [`try_quine_static_with`] builds such values on first use instead.
+/// Also fails if the value or its type cannot be represented, see [`QuineType`].
+///
+/// # Panics
+/// If `name` is not a valid identifier.
+pub fn try_quine_const_with<T: QuineType>(
+    name: &str,
+    value: &T,
+    ctx: &QuineContext,
+) -> Result<TokenStream, QuineError> {
+    let (ident, ty, ctor, const_eval) = quine_item(name, value, ctx)?;
+    if !const_eval {
+        return Err(QuineError::not_const(std::any::type_name::<T>()));
+    }
+    Ok(quote! {const #ident: #ty = #ctor;})
+}
+
+/// Infallible version of [`try_quine_const_with`] using the default [`QuineContext`].
+///
+/// # Panics
+/// If `try_quine_const_with` returns an error.
+pub fn quine_const<T: QuineType>(name: &str, value: &T) -> TokenStream {
+    try_quine_const_with(name, value, &QuineContext::default())
+        .unwrap_or_else(|err| panic!("{err}"))
+}
+
+/// The name, type and constructor tokens of an item holding `value`,
+/// and whether the constructor can be evaluated in a `const` context.
+fn quine_item<T: QuineType>(
+    name: &str,
+    value: &T,
+    ctx: &QuineContext,
+) -> Result<(TokenStream, TokenStream, TokenStream, bool), QuineError> {
+    let mut ident = TokenStream::new();
+    append_ident(&mut ident, name);
+    let ty = T::try_type_tokens_with(ctx)?;
+    let mut ctor = TokenStream::new();
+    let const_eval = Emitter::run_const_eval(value, ctx, &mut ctor)?;
+    Ok((ident, ty, ctor, const_eval))
+}
@@ -1,6 +1,40 @@
+// Lets the code generated by `#[derive(Quine)]` refer to `::polyquine` from within this crate.
+extern crate self as polyquine;
+
 #[macro_use]
 pub mod macros;
+mod context;
+mod dedup;
+mod emit;
+mod error;
+mod items;
+mod paths;
 mod quine;
+mod tokens;
 
+pub use context::{
+    Cycles, Dedup, MapOrder, Nesting, QuineContext, SequenceStyle, StdPaths, TypeArgs, TypePaths,
+};
+pub use dedup::DedupReport;
+pub use emit::{EmitItem, Emitter};
+pub use error::{FieldPath, PathSegment, QuineError, QuineErrorKind};
+pub use items::{quine_const, quine_static, try_quine_const_with, try_quine_static_with};
+pub use paths::{CratePath, resolve_dollar_crate};
 pub use polyquine_derive::Quine;
-pub use quine::Quine;
+pub use quine::{Quine, QuineType};
+
+/// Items used by the code that `#[derive(Quine)]` and the `derive_*!` macros generate. Not public API.
+///
+/// Re-exporting `proc_macro2`, `quote` and `syn` means that crates deriving `Quine` need not depend on them.
+#[doc(hidden)]
+pub mod __private {
+    pub use crate::paths::{TypePathCache, TypePathDecl};
+    pub use crate::quine::LexCmp;
+    pub use crate::tokens::{
+        TypeTokensFn, append_comma, append_field, append_group, append_ident, append_infer,
+        append_segment, append_turbofish, append_type_args,
+    };
+    pub use proc_macro2;
+    pub use quote;
+    pub use syn;
+}
@@ -1,15 +1,68 @@
+/// Derive `Quine` for types that implement `quote::ToTokens` as a literal of themselves.
+/// With `ord:` in front, the types must also implement `Ord`, which orders them in unordered collections.
 #[macro_export]
 macro_rules! derive_primitive {
+    (ord: $($type:ty),+) => {
+        $(
+            impl $crate::Quine for $type {
+                fn try_to_ctor_tokens_with(
+                    &self,
+                    _ctx: &$crate::QuineContext,
+                    out: &mut $crate::__private::proc_macro2::TokenStream,
+                ) -> Result<(), $crate::QuineError> {
+                    $crate::__private::quote::ToTokens::to_tokens(self, out);
+                    Ok(())
+                }
+
+                // A literal, which is `const`
+                fn emit<'a>(&'a self, e: &mut $crate::Emitter<'a>) -> Result<(), $crate::QuineError> {
+                    e.append(|out| $crate::__private::quote::ToTokens::to_tokens(self, out));
+                    Ok(())
+                }
+
+                fn try_to_type_tokens_with(
+                    _ctx: &$crate::QuineContext,
+                    out: &mut $crate::__private::proc_macro2::TokenStream,
+                ) -> Result<(), $crate::QuineError> {
+                    out.extend($crate::__private::quote::quote! {$type});
+                    Ok(())
+                }
+
+                fn quine_cmp(&self, other: &Self) -> Option<::core::cmp::Ordering> {
+                    Some(::core::cmp::Ord::cmp(self, other))
+                }
+            }
+        )+
+    };
     ($type:ty) => {
-        impl Quine for $type {
-            fn ctor_tokens(&self) -> TokenStream {
-                self.to_token_stream()
+        impl $crate::Quine for $type {
+            fn try_to_ctor_tokens_with(
+                &self,
+                _ctx: &$crate::QuineContext,
+                out: &mut $crate::__private::proc_macro2::TokenStream,
+            ) -> Result<(), $crate::QuineError> {
+                $crate::__private::quote::ToTokens::to_tokens(self, out);
+                Ok(())
+            }
+
+            // A literal, which is `const`
+            fn emit<'a>(&'a self, e: &mut $crate::Emitter<'a>) -> Result<(), $crate::QuineError> {
+                e.append(|out| $crate::__private::quote::ToTokens::to_tokens(self, out));
+                Ok(())
+            }
+
+            fn try_to_type_tokens_with(
+                _ctx: &$crate::QuineContext,
+                out: &mut $crate::__private::proc_macro2::TokenStream,
+            ) -> Result<(), $crate::QuineError> {
+                out.extend($crate::__private::quote::quote! {$type});
+                Ok(())
             }
         }
     };
     ($type:ty, $($rest:ty),*) => {
-        derive_primitive!($type);
-        derive_primitive!($($rest),*);
+        $crate::derive_primitive!($type);
+        $crate::derive_primitive!($($rest),*);
     };
 }
 
@@ -17,49 +70,156 @@ macro_rules! derive_primitive {
 /// - T supports `.iter()` over its elements `&V`
 /// - V implements `Quine`
 /// - T implements `From<[V; N]>`
+///
+/// Maps, whose `.iter()` is over `(&K, &V)`, are emitted from `[(K, V); N]`.
+///
+/// In const form (see `Emitter::enter_const`), T is emitted as the slice `&[..]`.
 #[macro_export]
 macro_rules! derive_iterable {
     // ::fully::qualified::GenericType<A, B, ...>
     ($(::)? $($pth:ident)::+ $type:ident < $($param:ident),* >) => {
-        impl<$($param: Quine),*> Quine for ($($pth)::* $type)<$($param),*> {
-            fn ctor_tokens(&self) -> TokenStream {
-                let inner = self
-                    .iter()
-                    .map(|item| item.ctor_tokens())
-                    .collect::<Vec<_>>();
-                quote! {
-                    $($pth)::* $type::from([#(#inner),*])
+        impl<$($param: $crate::Quine),*> $crate::Quine for ($($pth)::* $type)<$($param),*> {
+            fn try_to_ctor_tokens_with(
+                &self,
+                ctx: &$crate::QuineContext,
+                out: &mut $crate::__private::proc_macro2::TokenStream,
+            ) -> Result<(), $crate::QuineError> {
+                $crate::Emitter::run(self, ctx, out)
+            }
+
+            fn emit<'a>(&'a self, e: &mut $crate::Emitter<'a>) -> Result<(), $crate::QuineError> {
+                // A slice in const form
+                if e.is_const() {
+                    e.append(|out| out.extend($crate::__private::quote::quote! {&}));
+                    e.open($crate::__private::proc_macro2::Delimiter::Bracket);
+                    e.items(self.iter());
+                    e.close();
+                    return Ok(());
                 }
+                e.non_const(::core::any::type_name::<Self>())?;
+                let ctx = e.ctx();
+                e.try_append(|out| {
+                    out.extend($crate::__private::quote::quote! {$($pth)::* $type});
+                    $crate::__private::append_turbofish(
+                        ctx,
+                        out,
+                        &[$(<$param as $crate::Quine>::try_to_type_tokens_with),*],
+                    )?;
+                    $crate::__private::append_segment(out, "from");
+                    Ok(())
+                })?;
+                e.open($crate::__private::proc_macro2::Delimiter::Parenthesis);
+                e.open($crate::__private::proc_macro2::Delimiter::Bracket);
+                e.items(self.iter());
+                e.close();
+                e.close();
+                Ok(())
+            }
+
+            fn try_to_type_tokens_with(
+                ctx: &$crate::QuineContext,
+                out: &mut $crate::__private::proc_macro2::TokenStream,
+            ) -> Result<(), $crate::QuineError> {
+                out.extend($crate::__private::quote::quote! {$($pth)::* $type});
+                $crate::__private::append_type_args(
+                    ctx,
+                    out,
+                    &[$(<$param as $crate::Quine>::try_to_type_tokens_with),*],
+                )
             }
         }
     };
 
     // GenericType<A, B, ...>
     ($type:ident < $($param:ident),* >) => {
-        impl<$($param: Quine),*> Quine for $type<$($param),*> {
-            fn ctor_tokens(&self) -> TokenStream {
-                let inner = self
-                    .iter()
-                    .map(|item| item.ctor_tokens())
-                    .collect::<Vec<_>>();
-                quote! {
-                    $type::from([#(#inner),*])
+        impl<$($param: $crate::Quine),*> $crate::Quine for $type<$($param),*> {
+            fn try_to_ctor_tokens_with(
+                &self,
+                ctx: &$crate::QuineContext,
+                out: &mut $crate::__private::proc_macro2::TokenStream,
+            ) -> Result<(), $crate::QuineError> {
+                $crate::Emitter::run(self, ctx, out)
+            }
+
+            fn emit<'a>(&'a self, e: &mut $crate::Emitter<'a>) -> Result<(), $crate::QuineError> {
+                // A slice in const form
+                if e.is_const() {
+                    e.append(|out| out.extend($crate::__private::quote::quote! {&}));
+                    e.open($crate::__private::proc_macro2::Delimiter::Bracket);
+                    e.items(self.iter());
+                    e.close();
+                    return Ok(());
                 }
+                e.non_const(::core::any::type_name::<Self>())?;
+                let ctx = e.ctx();
+                e.try_append(|out| {
+                    out.extend($crate::__private::quote::quote! {$type});
+                    $crate::__private::append_turbofish(
+                        ctx,
+                        out,
+                        &[$(<$param as $crate::Quine>::try_to_type_tokens_with),*],
+                    )?;
+                    $crate::__private::append_segment(out, "from");
+                    Ok(())
+                })?;
+                e.open($crate::__private::proc_macro2::Delimiter::Parenthesis);
+                e.open($crate::__private::proc_macro2::Delimiter::Bracket);
+                e.items(self.iter());
+                e.close();
+                e.close();
+                Ok(())
+            }
+
+            fn try_to_type_tokens_with(
+                ctx: &$crate::QuineContext,
+                out: &mut $crate::__private::proc_macro2::TokenStream,
+            ) -> Result<(), $crate::QuineError> {
+                out.extend($crate::__private::quote::quote! {$type});
+                $crate::__private::append_type_args(
+                    ctx,
+                    out,
+                    &[$(<$param as $crate::Quine>::try_to_type_tokens_with),*],
+                )
             }
         }
     };
 
     // NonGenericType
     ($type:ident) => {
-        impl Quine for $type {
-            fn ctor_tokens(&self) -> TokenStream {
-                let inner = self
-                    .iter()
-                    .map(|item| item.ctor_tokens())
-                    .collect::<Vec<_>>();
-                quote! {
-                    $type::from([#(#inner),*])
+        impl $crate::Quine for $type {
+            fn try_to_ctor_tokens_with(
+                &self,
+                ctx: &$crate::QuineContext,
+                out: &mut $crate::__private::proc_macro2::TokenStream,
+            ) -> Result<(), $crate::QuineError> {
+                $crate::Emitter::run(self, ctx, out)
+            }
+
+            fn emit<'a>(&'a self, e: &mut $crate::Emitter<'a>) -> Result<(), $crate::QuineError> {
+                // A slice in const form
+                if e.is_const() {
+                    e.append(|out| out.extend($crate::__private::quote::quote! {&}));
+                    e.open($crate::__private::proc_macro2::Delimiter::Bracket);
+                    e.items(self.iter());
+                    e.close();
+                    return Ok(());
                 }
+                e.non_const(::core::any::type_name::<Self>())?;
+                e.append(|out| out.extend($crate::__private::quote::quote! {$type::from}));
+                e.open($crate::__private::proc_macro2::Delimiter::Parenthesis);
+                e.open($crate::__private::proc_macro2::Delimiter::Bracket);
+                e.items(self.iter());
+                e.close();
+                e.close();
+                Ok(())
+            }
+
+            fn try_to_type_tokens_with(
+                _ctx: &$crate::QuineContext,
+                out: &mut $crate::__private::proc_macro2::TokenStream,
+            ) -> Result<(), $crate::QuineError> {
+                out.extend($crate::__private::quote::quote! {$type});
+                Ok(())
             }
         }
     };
@@ -70,29 +230,75 @@ macro_rules! derive_iterable {
 #[macro_export]
 macro_rules! derive_tuple {
     ( $( $name:ident )+ ) => {
-        impl<$($name: Quine),*> Quine for ($($name,)*)
+        impl<$($name: $crate::Quine),*> $crate::Quine for ($($name,)*)
         {
-            fn ctor_tokens(&self) -> TokenStream {
+            fn try_to_ctor_tokens_with(
+                &self,
+                ctx: &$crate::QuineContext,
+                out: &mut $crate::__private::proc_macro2::TokenStream,
+            ) -> Result<(), $crate::QuineError> {
+                $crate::Emitter::run(self, ctx, out)
+            }
+
+            fn emit<'a>(&'a self, e: &mut $crate::Emitter<'a>) -> Result<(), $crate::QuineError> {
                 #[allow(non_snake_case)]
                 let ($($name,)*) = self;
-                let ctors = vec![$($name.ctor_tokens()),*];
-                quote! {
-                    (#(#ctors),*)
+                e.open($crate::__private::proc_macro2::Delimiter::Parenthesis);
+                for (i, field) in [$($name as &'a dyn $crate::Quine),*].into_iter().enumerate() {
+                    if i > 0 {
+                        e.append($crate::__private::append_comma);
+                    }
+                    e.value_at($crate::PathSegment::TupleField(i), field);
                 }
+                e.close();
+                Ok(())
+            }
+
+            fn quine_cmp(&self, other: &Self) -> Option<::core::cmp::Ordering> {
+                #[allow(non_snake_case)]
+                let ($($name,)*) = self;
+                let lhs = $crate::derive_tuple!(@nest $($name)*);
+                #[allow(non_snake_case)]
+                let ($($name,)*) = other;
+                let rhs = $crate::derive_tuple!(@nest $($name)*);
+                $crate::__private::LexCmp::lex_cmp(&lhs, &rhs)
+            }
+
+            fn try_to_type_tokens_with(
+                ctx: &$crate::QuineContext,
+                out: &mut $crate::__private::proc_macro2::TokenStream,
+            ) -> Result<(), $crate::QuineError> {
+                $crate::__private::append_group(
+                    out,
+                    $crate::__private::proc_macro2::Delimiter::Parenthesis,
+                    |out| {
+                        $(
+                            <$name as $crate::Quine>::try_to_type_tokens_with(ctx, out)?;
+                            $crate::__private::append_comma(out);
+                        )*
+                        Ok(())
+                    },
+                )
             }
         }
     };
+
+    // The fields `a b c` as `(a, (b, (c, ())))`, for `LexCmp`
+    (@nest) => { () };
+    (@nest $head:ident $($rest:ident)*) => {
+        ($head, $crate::derive_tuple!(@nest $($rest)*))
+    };
 }
 
 #[macro_export]
 macro_rules! derive_tuple_all {
     ( $one:ident ) => {
-        derive_tuple!($one);
+        $crate::derive_tuple!($one);
     };
 
     ( $head:ident $($rest:ident)* ) => {
-        derive_tuple!($head $($rest)*);
-        derive_tuple_all!($($rest)*);
+        $crate::derive_tuple!($head $($rest)*);
+        $crate::derive_tuple_all!($($rest)*);
     };
 }
 
@@ -101,17 +307,30 @@ macro_rules! derive_tuple_all {
 #[macro_export]
 macro_rules! derive_trivial {
     ($type:ty) => {
-        impl Quine for $type {
-            fn ctor_tokens(&self) -> TokenStream {
-                quote! {
+        impl $crate::Quine for $type {
+            fn try_to_ctor_tokens_with(
+                &self,
+                _ctx: &$crate::QuineContext,
+                out: &mut $crate::__private::proc_macro2::TokenStream,
+            ) -> Result<(), $crate::QuineError> {
+                out.extend($crate::__private::quote::quote! {
                     $type::from(#self)
-                }
+                });
+                Ok(())
+            }
+
+            fn try_to_type_tokens_with(
+                _ctx: &$crate::QuineContext,
+                out: &mut $crate::__private::proc_macro2::TokenStream,
+            ) -> Result<(), $crate::QuineError> {
+                out.extend($crate::__private::quote::quote! {$type});
+                Ok(())
             }
         }
     };
 
     ($type:ty, $($rest:ty),*) => {
-        derive_trivial!($type);
-        derive_trivial!($($rest),*);
+        $crate::derive_trivial!($type);
+        $crate::derive_trivial!($($rest),*);
     }
 }
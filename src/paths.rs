@@ -0,0 +1,190 @@
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+use proc_macro2::{Group, Ident, Punct, Spacing, Span, TokenStream, TokenTree};
+use quote::TokenStreamExt;
+
+use crate::tokens::{append_ident, append_segment};
+use crate::{QuineContext, QuineError, TypePaths};
+
+/// How the defining crate of a type that derives `Quine` is named in its emitted path.
+///
+/// Set it per type with `#[polyquine(path(..))]`,
+/// or per defining crate at emit time with [`QuineContext::with_crate_path`](crate::QuineContext::with_crate_path).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum CratePath {
+    /// The crate's own name, as in `module_path!()`: `my_crate::module::Type`.
+    #[default]
+    Name,
+    /// `crate::module::Type`, for tokens used inside the defining crate.
+    /// Set with `#[polyquine(path(crate))]`.
+    Crate,
+    /// `::alias::module::Type`, for a dependency renamed in `Cargo.toml`.
+    /// Set with `#[polyquine(path(alias = my_alias))]`, or build it with [`CratePath::alias`], which checks the name.
+    /// Emitting a path with an alias that is not a valid identifier fails with
+    /// [`QuineErrorKind::InvalidIdent`](crate::QuineErrorKind::InvalidIdent).
+    Alias(Cow<'static, str>),
+    /// `$crate::module::Type`, for tokens pasted into a `macro_rules!` of the defining crate,
+    /// or resolved later with [`resolve_dollar_crate`].
+    /// Set with `#[polyquine(path($crate))]`.
+    DollarCrate,
+}
+
+impl CratePath {
+    /// [`CratePath::Alias`], if `alias` is a valid identifier, e.g. `my_alias` or `r#async`;
+    /// a package name such as `my-crate` is not.
+    pub fn alias(alias: impl Into<Cow<'static, str>>) -> Result<Self, QuineError> {
+        let alias = alias.into();
+        check_ident(&alias)?;
+        Ok(CratePath::Alias(alias))
+    }
+}
+
+/// Fail with [`QuineErrorKind::InvalidIdent`](crate::QuineErrorKind::InvalidIdent) if `name` is not an identifier.
+fn check_ident(name: &str) -> Result<(), QuineError> {
+    match syn::parse_str::<Ident>(name) {
+        Ok(_) => Ok(()),
+        Err(_) => Err(QuineError::invalid_ident(name)),
+    }
+}
+
+/// Where a type that derives `Quine` is defined, as declared by the derive macro.
+#[doc(hidden)]
+pub struct TypePathDecl {
+    pub ident: &'static str,
+    pub module_path: &'static str,
+    /// From `#[polyquine(prefix = ..)]`
+    pub prefix: Option<fn() -> TokenStream>,
+    /// From `#[polyquine(path(..))]`
+    pub krate: CratePath,
+}
+
+impl TypePathDecl {
+    /// The name of the defining crate, as it appears in `module_path!()`.
+    fn crate_name(&self) -> &'static str {
+        self.module_path.split("::").next().unwrap_or_default()
+    }
+
+    fn resolve(&self, type_paths: TypePaths, krate: &CratePath) -> Result<TokenStream, QuineError> {
+        match (type_paths, self.prefix) {
+            (TypePaths::Unqualified, _) => {
+                let mut path = TokenStream::new();
+                append_ident(&mut path, self.ident);
+                Ok(path)
+            }
+            (TypePaths::Qualified, Some(prefix)) => {
+                let mut path = prefix();
+                append_segment(&mut path, self.ident);
+                Ok(path)
+            }
+            (TypePaths::Qualified, None) => module_type_path(self.module_path, self.ident, krate),
+        }
+    }
+}
+
+/// Per-type cache of the resolved path, so that it is built once rather than for every value.
+/// The derive macro keeps one in a `thread_local!` for each type, since `TokenStream`s cannot be shared between threads.
+/// It holds the path for the most recently used context settings.
+#[doc(hidden)]
+pub struct TypePathCache {
+    cached: RefCell<Option<(TypePaths, CratePath, TokenStream)>>,
+}
+
+impl TypePathCache {
+    #[allow(clippy::new_without_default)]
+    pub const fn new() -> Self {
+        Self {
+            cached: RefCell::new(None),
+        }
+    }
+
+    pub fn get(&self, ctx: &QuineContext, decl: &TypePathDecl) -> Result<TokenStream, QuineError> {
+        let type_paths = ctx.type_paths();
+        let krate = ctx.crate_path(decl.crate_name()).unwrap_or(&decl.krate);
+
+        let mut cached = self.cached.borrow_mut();
+        if let Some((cached_type_paths, cached_krate, path)) = &*cached
+            && *cached_type_paths == type_paths
+            && cached_krate == krate
+        {
+            return Ok(path.clone());
+        }
+
+        let path = decl.resolve(type_paths, krate)?;
+        *cached = Some((type_paths, krate.clone(), path.clone()));
+        Ok(path)
+    }
+}
+
+/// Build the path of a type from the `module_path!()` of its definition.
+/// The first segment of `module_path` is the name of the defining crate, which is replaced according to `krate`.
+/// Fails if `krate` is an alias that is not a valid identifier.
+pub(crate) fn module_type_path(
+    module_path: &str,
+    ident: &str,
+    krate: &CratePath,
+) -> Result<TokenStream, QuineError> {
+    let mut segments = module_path.split("::");
+    let crate_name = segments.next().unwrap_or_default();
+
+    let mut path = TokenStream::new();
+    match krate {
+        CratePath::Name => path.append(Ident::new(crate_name, Span::call_site())),
+        CratePath::Crate => path.append(Ident::new("crate", Span::call_site())),
+        CratePath::Alias(alias) => {
+            check_ident(alias)?;
+            append_segment(&mut path, alias);
+        }
+        CratePath::DollarCrate => {
+            path.append(Punct::new('$', Spacing::Alone));
+            path.append(Ident::new("crate", Span::call_site()));
+        }
+    }
+    for segment in segments.chain([ident]) {
+        append_segment(&mut path, segment);
+    }
+    Ok(path)
+}
+
+/// Replace every `$crate` in `tokens` with `krate`, e.g. `quote!(crate)` or `quote!(::my_alias)`.
+///
+/// Used to resolve paths emitted with [`CratePath::DollarCrate`] once it is known where the tokens end up.
+pub fn resolve_dollar_crate(tokens: TokenStream, krate: &TokenStream) -> TokenStream {
+    // Explicit stack of partially rebuilt groups, so that deeply nested tokens cannot overflow the call stack
+    let mut stack: Vec<(
+        Option<Group>,
+        TokenStream,
+        proc_macro2::token_stream::IntoIter,
+    )> = vec![(None, TokenStream::new(), tokens.into_iter())];
+
+    loop {
+        let (_, out, iter) = stack.last_mut().expect("stack is never empty");
+        match iter.next() {
+            Some(TokenTree::Punct(dollar)) if dollar.as_char() == '$' => {
+                let mut lookahead = iter.clone();
+                match lookahead.next() {
+                    Some(TokenTree::Ident(ident)) if ident == "crate" => {
+                        *iter = lookahead;
+                        out.extend(krate.clone());
+                    }
+                    _ => out.append(dollar),
+                }
+            }
+            Some(TokenTree::Group(group)) => {
+                let inner = group.stream().into_iter();
+                stack.push((Some(group), TokenStream::new(), inner));
+            }
+            Some(token) => out.append(token),
+            None => {
+                let (group, out, _) = stack.pop().expect("stack is never empty");
+                let Some(group) = group else {
+                    return out;
+                };
+                let mut resolved = Group::new(group.delimiter(), out);
+                resolved.set_span(group.span());
+                let (_, parent, _) = stack.last_mut().expect("groups always have a parent");
+                parent.append(resolved);
+            }
+        }
+    }
+}
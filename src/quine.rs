@@ -1,71 +1,949 @@
+use std::cell::{Cell, RefCell};
+use std::cmp::Ordering;
 use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::marker::PhantomData;
+use std::rc::{Rc, Weak as RcWeak};
+use std::sync::{Arc, Weak as ArcWeak};
 
-use proc_macro2::TokenStream;
-use quote::{ToTokens, quote};
+use proc_macro2::{Delimiter, Punct, Spacing, TokenStream};
+use quote::{ToTokens, TokenStreamExt, quote};
 use ustr::Ustr;
 
+use crate::context::StdItem;
+use crate::tokens::{
+    TypeTokensFn, append_group, append_ident, append_infer, append_segment, append_turbofish,
+    append_type_args,
+};
+use crate::{Emitter, MapOrder, QuineContext, QuineError, SequenceStyle, TypeArgs};
+
 pub trait Quine {
-    fn ctor_tokens(&self) -> TokenStream;
+    /// Append tokens for a Rust expression that evaluates to `self`, shaped by `ctx`, to `out`.
+    /// Fails if some part of the value cannot be represented, e.g. a skipped enum variant;
+    /// `out` may then contain part of the expression.
+    ///
+    /// This is the method implementations provide, in the style of [`ToTokens::to_tokens`]:
+    /// nested values append to the same stream instead of allocating their own.
+    fn try_to_ctor_tokens_with(
+        &self,
+        ctx: &QuineContext,
+        out: &mut TokenStream,
+    ) -> Result<(), QuineError>;
+
+    /// Describe the constructor tokens of `self` to an [`Emitter`], which visits nested values without recursion.
+    ///
+    /// The default appends the tokens of [`Quine::try_to_ctor_tokens_with`],
+    /// so values of types that do not override it are emitted recursively,
+    /// and marks them as not evaluable in a `const` context (see [`Emitter::non_const`]), which they may not be.
+    /// `#[derive(Quine)]` and the implementations for `Box`, `Option`, tuples and collections override it,
+    /// and implement `try_to_ctor_tokens_with` with [`Emitter::run`].
+    fn emit<'a>(&'a self, e: &mut Emitter<'a>) -> Result<(), QuineError> {
+        let ctx = e.ctx();
+        e.non_const(std::any::type_name::<Self>())?;
+        e.try_append(|out| self.try_to_ctor_tokens_with(ctx, out))
+    }
+
+    /// Compare `self` to `other`, to emit the entries of unordered collections such as `HashSet` in a stable order
+    /// (see [`MapOrder::Sorted`]).
+    ///
+    /// Types with a total order, e.g. through `Ord`, return `Some` for every pair of values.
+    /// The default returns `None`, and the entries are ordered by the string form of their tokens instead.
+    fn quine_cmp(&self, _other: &Self) -> Option<Ordering>
+    where
+        Self: Sized,
+    {
+        None
+    }
+
+    /// Append the tokens of the type `Self`, e.g. `::std::vec::Vec<i32>`, shaped by `ctx`,
+    /// which [`TypeArgs::Turbofish`] writes as the type arguments of generic constructors
+    /// and [`QuineType`] generates on its own.
+    ///
+    /// The default fails with [`QuineErrorKind::UnnamedType`](crate::QuineErrorKind::UnnamedType).
+    /// `#[derive(Quine)]` and the built-in implementations override it;
+    /// the type parameters of a derived type that are not bounded by `Quine` are written as `_`.
+    fn try_to_type_tokens_with(ctx: &QuineContext, out: &mut TokenStream) -> Result<(), QuineError>
+    where
+        Self: Sized,
+    {
+        let _ = (ctx, out);
+        Err(QuineError::unnamed_type(std::any::type_name::<Self>()))
+    }
+
+    /// The name of the type of `self`, from [`std::any::type_name`].
+    /// [`Dedup::Subtrees`](crate::Dedup::Subtrees) only shares a subtree between values of the same type,
+    /// since equal tokens may build values of different types, e.g. an empty `Vec::from([])`.
+    ///
+    /// Implementations do not override it.
+    fn quine_type_name(&self) -> &'static str {
+        std::any::type_name::<Self>()
+    }
+
+    /// Generate tokens for a Rust expression that evaluates to `self`, shaped by `ctx`.
+    /// Fails if some part of the value cannot be represented, e.g. a skipped enum variant.
+    fn try_ctor_tokens_with(&self, ctx: &QuineContext) -> Result<TokenStream, QuineError> {
+        let mut out = TokenStream::new();
+        self.try_to_ctor_tokens_with(ctx, &mut out)?;
+        Ok(out)
+    }
+
+    /// [`Quine::try_ctor_tokens_with`] using the default [`QuineContext`].
+    fn try_ctor_tokens(&self) -> Result<TokenStream, QuineError> {
+        self.try_ctor_tokens_with(&QuineContext::default())
+    }
+
+    /// Infallible version of [`Quine::try_to_ctor_tokens_with`] using the default [`QuineContext`].
+    ///
+    /// # Panics
+    /// If `try_to_ctor_tokens_with` returns an error.
+    fn to_ctor_tokens(&self, out: &mut TokenStream) {
+        self.try_to_ctor_tokens_with(&QuineContext::default(), out)
+            .unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    /// Infallible version of [`Quine::try_ctor_tokens_with`].
+    ///
+    /// # Panics
+    /// If `try_ctor_tokens_with` returns an error.
+    fn ctor_tokens_with(&self, ctx: &QuineContext) -> TokenStream {
+        self.try_ctor_tokens_with(ctx)
+            .unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    /// Infallible version of [`Quine::try_ctor_tokens`].
+    ///
+    /// # Panics
+    /// If `try_ctor_tokens` returns an error.
+    fn ctor_tokens(&self) -> TokenStream {
+        self.ctor_tokens_with(&QuineContext::default())
+    }
+}
+
+/// The type of a value, e.g. for `static NAME: <type> = <ctor>;` or `let x: <type> = <ctor>;`.
+///
+/// Implemented for every sized type that implements [`Quine`], through [`Quine::try_to_type_tokens_with`],
+/// so `#[derive(Quine)]` derives it too.
+/// The type of a generic type is its concrete instantiation, e.g. `::std::vec::Vec<::std::string::String>`;
+/// type parameters of derived types that are not bounded by `Quine`, such as that of a `PhantomData<T>`, are written as `_`,
+/// which a `let` can infer but a `static` cannot.
+pub trait QuineType: Quine + Sized {
+    /// Generate the tokens of the type `Self`, shaped by `ctx`.
+    /// Fails if some part of the type cannot be written out, see [`QuineErrorKind::UnnamedType`](crate::QuineErrorKind::UnnamedType).
+    fn try_type_tokens_with(ctx: &QuineContext) -> Result<TokenStream, QuineError> {
+        let mut out = TokenStream::new();
+        Self::try_to_type_tokens_with(ctx, &mut out)?;
+        Ok(out)
+    }
+
+    /// [`QuineType::try_type_tokens_with`] using the default [`QuineContext`].
+    fn try_type_tokens() -> Result<TokenStream, QuineError> {
+        Self::try_type_tokens_with(&QuineContext::default())
+    }
+
+    /// Infallible version of [`QuineType::try_type_tokens_with`].
+    ///
+    /// # Panics
+    /// If `try_type_tokens_with` returns an error.
+    fn type_tokens_with(ctx: &QuineContext) -> TokenStream {
+        Self::try_type_tokens_with(ctx).unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    /// Infallible version of [`QuineType::try_type_tokens`].
+    ///
+    /// # Panics
+    /// If `try_type_tokens` returns an error.
+    fn type_tokens() -> TokenStream {
+        Self::type_tokens_with(&QuineContext::default())
+    }
+}
+
+impl<T: Quine> QuineType for T {}
+
+/// Compare two sequences lexicographically with [`Quine::quine_cmp`].
+fn cmp_items<'a, T: Quine + 'a>(
+    a: impl IntoIterator<Item = &'a T>,
+    b: impl IntoIterator<Item = &'a T>,
+) -> Option<Ordering> {
+    let mut a = a.into_iter();
+    let mut b = b.into_iter();
+    loop {
+        match (a.next(), b.next()) {
+            (Some(x), Some(y)) => match x.quine_cmp(y)? {
+                Ordering::Equal => {}
+                ordering => return Some(ordering),
+            },
+            (x, y) => return Some(x.is_some().cmp(&y.is_some())),
+        }
+    }
+}
+
+/// The entries of an unordered collection in a stable order:
+/// by [`Quine::quine_cmp`] of their `key` if it orders every one of them, otherwise by [`sorted_by_ctor`].
+fn sorted_entries<E: Quine, K: Quine>(
+    entries: impl IntoIterator<Item = E>,
+    key: impl Fn(&E) -> &K,
+    ctx: &QuineContext,
+) -> Result<Vec<E>, QuineError> {
+    let mut entries: Vec<E> = entries.into_iter().collect();
+    let unordered = Cell::new(false);
+    entries.sort_by(|a, b| {
+        key(a).quine_cmp(key(b)).unwrap_or_else(|| {
+            unordered.set(true);
+            Ordering::Equal
+        })
+    });
+    if unordered.get() {
+        return sorted_by_ctor(entries, key, ctx);
+    }
+    Ok(entries)
+}
+
+/// The entries of an unordered collection, sorted by the string form of the constructor tokens of their `key`.
+///
+/// The entries are then emitted in this order by the same [`Emitter`] rather than pasted in,
+/// so that the allocations they share with the rest of the value stay shared.
+/// A key may not be emitted on its own, e.g. if it holds a `Weak` to an allocation elsewhere in the value:
+/// there is then no stable order, which is an error.
+fn sorted_by_ctor<E: Quine, K: Quine>(
+    entries: Vec<E>,
+    key: impl Fn(&E) -> &K,
+    ctx: &QuineContext,
+) -> Result<Vec<E>, QuineError> {
+    let ctors = entries
+        .iter()
+        .map(|entry| {
+            key(entry)
+                .try_ctor_tokens_with(ctx)
+                .map(|ctor| ctor.to_string())
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| QuineError::unsortable_key(std::any::type_name::<K>()))?;
+    let mut keyed: Vec<(String, E)> = ctors.into_iter().zip(entries).collect();
+    keyed.sort_by(|(a, _), (b, _)| a.cmp(b));
+    Ok(keyed.into_iter().map(|(_, entry)| entry).collect())
+}
+
+/// [`Quine::quine_cmp`] of tuples, whose fields `derive_tuple!` nests into `(&field, (.., ()))` pairs:
+/// the lexicographic order of the fields, or `None` if any of them has no order.
+#[doc(hidden)]
+pub trait LexCmp {
+    fn lex_cmp(&self, other: &Self) -> Option<Ordering>;
+}
+
+impl LexCmp for () {
+    fn lex_cmp(&self, _other: &Self) -> Option<Ordering> {
+        Some(Ordering::Equal)
+    }
+}
+
+impl<H: Quine, T: LexCmp> LexCmp for (&H, T) {
+    fn lex_cmp(&self, other: &Self) -> Option<Ordering> {
+        let head = self.0.quine_cmp(other.0)?;
+        let tail = self.1.lex_cmp(&other.1)?;
+        Some(head.then(tail))
+    }
+}
+
+/// Emit `path::from([..])` for the collection `C`, with the elements of the array emitted by `f`,
+/// and the type arguments `args` with [`TypeArgs::Turbofish`].
+/// In const form, emit the slice `&[..]` instead.
+fn emit_from_array<'a, C: ?Sized>(
+    e: &mut Emitter<'a>,
+    path: StdItem,
+    args: &[TypeTokensFn],
+    f: impl FnOnce(&mut Emitter<'a>) -> Result<(), QuineError>,
+) -> Result<(), QuineError> {
+    if e.is_const() {
+        e.append(|out| out.append(Punct::new('&', Spacing::Alone)));
+        e.open(Delimiter::Bracket);
+        f(e)?;
+        e.close();
+        return Ok(());
+    }
+    let ctx = e.ctx();
+    e.non_const(std::any::type_name::<C>())?;
+    e.try_append(|out| {
+        ctx.append_std_path(path, out);
+        append_turbofish(ctx, out, args)?;
+        append_segment(out, "from");
+        Ok(())
+    })?;
+    e.open(Delimiter::Parenthesis);
+    e.open(Delimiter::Bracket);
+    f(e)?;
+    e.close();
+    e.close();
+    Ok(())
+}
+
+/// Emit a `String`-like value as `path::from("s")`, or as the `&'static str` literal `"s"` in const form.
+fn emit_str<'a, T: Quine>(value: &'a T, s: &'a str, e: &mut Emitter<'a>) -> Result<(), QuineError> {
+    if e.is_const() {
+        e.append(|out| s.to_tokens(out));
+        return Ok(());
+    }
+    let ctx = e.ctx();
+    e.non_const(std::any::type_name::<T>())?;
+    e.try_append(|out| value.try_to_ctor_tokens_with(ctx, out))
+}
+
+/// Append `path::from("s")`.
+fn append_from_str(
+    path: StdItem,
+    s: &str,
+    ctx: &QuineContext,
+    out: &mut TokenStream,
+) -> Result<(), QuineError> {
+    ctx.append_std_path(path, out);
+    append_segment(out, "from");
+    append_group(out, Delimiter::Parenthesis, |out| {
+        s.to_tokens(out);
+        Ok(())
+    })
 }
 
 impl Quine for Ustr {
-    fn ctor_tokens(&self) -> TokenStream {
-        let s = self.as_str();
-        quote! {Ustr::from(#s)}
+    fn try_to_ctor_tokens_with(
+        &self,
+        ctx: &QuineContext,
+        out: &mut TokenStream,
+    ) -> Result<(), QuineError> {
+        append_from_str(StdItem::Ustr, self.as_str(), ctx, out)
+    }
+
+    fn emit<'a>(&'a self, e: &mut Emitter<'a>) -> Result<(), QuineError> {
+        emit_str(self, self.as_str(), e)
+    }
+
+    fn try_to_type_tokens_with(
+        ctx: &QuineContext,
+        out: &mut TokenStream,
+    ) -> Result<(), QuineError> {
+        ctx.append_std_path(StdItem::Ustr, out);
+        Ok(())
+    }
+
+    fn quine_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.as_str().cmp(other.as_str()))
     }
 }
 
-impl<T: Quine> Quine for &T {
-    fn ctor_tokens(&self) -> TokenStream {
-        (**self).ctor_tokens()
+impl Quine for String {
+    fn try_to_ctor_tokens_with(
+        &self,
+        ctx: &QuineContext,
+        out: &mut TokenStream,
+    ) -> Result<(), QuineError> {
+        append_from_str(StdItem::String, self.as_str(), ctx, out)
+    }
+
+    fn emit<'a>(&'a self, e: &mut Emitter<'a>) -> Result<(), QuineError> {
+        emit_str(self, self.as_str(), e)
+    }
+
+    fn try_to_type_tokens_with(
+        ctx: &QuineContext,
+        out: &mut TokenStream,
+    ) -> Result<(), QuineError> {
+        ctx.append_std_path(StdItem::String, out);
+        Ok(())
+    }
+
+    fn quine_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T: ?Sized> Quine for PhantomData<T> {
+    fn try_to_ctor_tokens_with(
+        &self,
+        ctx: &QuineContext,
+        out: &mut TokenStream,
+    ) -> Result<(), QuineError> {
+        ctx.append_std_path(StdItem::PhantomData, out);
+        Ok(())
+    }
+
+    fn emit<'a>(&'a self, e: &mut Emitter<'a>) -> Result<(), QuineError> {
+        let ctx = e.ctx();
+        e.append(|out| ctx.append_std_path(StdItem::PhantomData, out));
+        Ok(())
+    }
+
+    /// `T` may not implement `Quine`, so it is left for the compiler to infer.
+    fn try_to_type_tokens_with(
+        ctx: &QuineContext,
+        out: &mut TokenStream,
+    ) -> Result<(), QuineError> {
+        append_std_type(ctx, StdItem::PhantomData, &[append_infer], out)
+    }
+
+    fn quine_cmp(&self, _other: &Self) -> Option<Ordering> {
+        Some(Ordering::Equal)
+    }
+}
+
+impl<T: Quine + ?Sized> Quine for &T {
+    fn try_to_ctor_tokens_with(
+        &self,
+        ctx: &QuineContext,
+        out: &mut TokenStream,
+    ) -> Result<(), QuineError> {
+        (**self).try_to_ctor_tokens_with(ctx, out)
+    }
+
+    fn emit<'a>(&'a self, e: &mut Emitter<'a>) -> Result<(), QuineError> {
+        (**self).emit(e)
     }
 }
 
 impl<T: Quine> Quine for Box<T> {
-    fn ctor_tokens(&self) -> TokenStream {
-        let inner = self.as_ref().ctor_tokens();
-        quote! {Box::new(#inner)}
+    fn try_to_ctor_tokens_with(
+        &self,
+        ctx: &QuineContext,
+        out: &mut TokenStream,
+    ) -> Result<(), QuineError> {
+        Emitter::run(self, ctx, out)
+    }
+
+    fn emit<'a>(&'a self, e: &mut Emitter<'a>) -> Result<(), QuineError> {
+        // `&T` in const form
+        if e.is_const() {
+            e.append(|out| out.append(Punct::new('&', Spacing::Alone)));
+            e.value(self.as_ref());
+            return Ok(());
+        }
+        let ctx = e.ctx();
+        e.non_const(std::any::type_name::<Self>())?;
+        e.try_append(|out| {
+            ctx.append_std_path(StdItem::Box, out);
+            append_turbofish(ctx, out, &[T::try_to_type_tokens_with])?;
+            append_segment(out, "new");
+            Ok(())
+        })?;
+        e.open(Delimiter::Parenthesis);
+        e.value(self.as_ref());
+        e.close();
+        Ok(())
+    }
+
+    fn try_to_type_tokens_with(
+        ctx: &QuineContext,
+        out: &mut TokenStream,
+    ) -> Result<(), QuineError> {
+        append_std_type(ctx, StdItem::Box, &[T::try_to_type_tokens_with], out)
+    }
+
+    fn quine_cmp(&self, other: &Self) -> Option<Ordering> {
+        self.as_ref().quine_cmp(other.as_ref())
+    }
+}
+
+/// The path of `item`, e.g. to interpolate it more than once.
+fn std_path(ctx: &QuineContext, item: StdItem) -> TokenStream {
+    let mut path = TokenStream::new();
+    ctx.append_std_path(item, &mut path);
+    path
+}
+
+/// The path of the generic `item` in an expression, followed by the type arguments `args` with [`TypeArgs::Turbofish`].
+fn std_ctor_path(
+    ctx: &QuineContext,
+    item: StdItem,
+    args: &[TypeTokensFn],
+) -> Result<TokenStream, QuineError> {
+    let mut path = std_path(ctx, item);
+    append_turbofish(ctx, &mut path, args)?;
+    Ok(path)
+}
+
+/// Append the type `item<args>`.
+fn append_std_type(
+    ctx: &QuineContext,
+    item: StdItem,
+    args: &[TypeTokensFn],
+    out: &mut TokenStream,
+) -> Result<(), QuineError> {
+    ctx.append_std_path(item, out);
+    append_type_args(ctx, out, args)
+}
+
+/// `Rc` and `Arc` of a value, a `str` and a slice, and their `Weak` pointers.
+/// Unlike clones of a `Box`, clones of them point to the same allocation, which the output preserves:
+/// a graph of `Rc`s is rebuilt with the same topology, see [`Emitter::shared`].
+macro_rules! derive_shared {
+    ($pointer:ident, $weak:ident, $item:expr, $weak_item:expr) => {
+        impl<T: Quine> Quine for $pointer<T> {
+            fn try_to_ctor_tokens_with(
+                &self,
+                ctx: &QuineContext,
+                out: &mut TokenStream,
+            ) -> Result<(), QuineError> {
+                Emitter::run(self, ctx, out)
+            }
+
+            fn emit<'a>(&'a self, e: &mut Emitter<'a>) -> Result<(), QuineError> {
+                let unique = $pointer::strong_count(self) == 1 && $pointer::weak_count(self) == 0;
+                let ptr = $pointer::as_ptr(self).cast::<()>();
+                let path = std_ctor_path(e.ctx(), $item, &[T::try_to_type_tokens_with])?;
+                e.non_const(std::any::type_name::<Self>())?;
+                e.shared(ptr, unique, path, true, |e| e.value(self.as_ref()))
+            }
+
+            fn try_to_type_tokens_with(
+                ctx: &QuineContext,
+                out: &mut TokenStream,
+            ) -> Result<(), QuineError> {
+                append_std_type(ctx, $item, &[T::try_to_type_tokens_with], out)
+            }
+        }
+
+        impl Quine for $pointer<str> {
+            fn try_to_ctor_tokens_with(
+                &self,
+                ctx: &QuineContext,
+                out: &mut TokenStream,
+            ) -> Result<(), QuineError> {
+                Emitter::run(self, ctx, out)
+            }
+
+            fn emit<'a>(&'a self, e: &mut Emitter<'a>) -> Result<(), QuineError> {
+                let unique = $pointer::strong_count(self) == 1 && $pointer::weak_count(self) == 0;
+                let ptr = $pointer::as_ptr(self).cast::<()>();
+                e.non_const(std::any::type_name::<Self>())?;
+                let path = std_path(e.ctx(), $item);
+                let s: &str = self;
+                // `::<str>` even with `TypeArgs::Inferred`, as the slice impl below keeps `[_]`:
+                // it is not an argument left to inference but picks `From<&str>`,
+                // since `Rc::from("..")` can just as well build an `Rc<&str>`.
+                let ctor = quote! {#path::<str>::from(#s)};
+                e.shared(ptr, unique, path, false, |e| {
+                    e.append(|out| out.extend(ctor))
+                })
+            }
+
+            fn try_to_type_tokens_with(
+                ctx: &QuineContext,
+                out: &mut TokenStream,
+            ) -> Result<(), QuineError> {
+                ctx.append_std_path($item, out);
+                out.extend(quote! {<str>});
+                Ok(())
+            }
+        }
+
+        impl<T: Quine> Quine for $pointer<[T]> {
+            fn try_to_ctor_tokens_with(
+                &self,
+                ctx: &QuineContext,
+                out: &mut TokenStream,
+            ) -> Result<(), QuineError> {
+                Emitter::run(self, ctx, out)
+            }
+
+            fn emit<'a>(&'a self, e: &mut Emitter<'a>) -> Result<(), QuineError> {
+                e.non_const(std::any::type_name::<Self>())?;
+                let unique = $pointer::strong_count(self) == 1 && $pointer::weak_count(self) == 0;
+                let ptr = $pointer::as_ptr(self).cast::<()>();
+                let ctx = e.ctx();
+                let path = std_path(ctx, $item);
+                let elem = match ctx.type_args() {
+                    TypeArgs::Inferred => quote! {_},
+                    TypeArgs::Turbofish => {
+                        let mut elem = TokenStream::new();
+                        T::try_to_type_tokens_with(ctx, &mut elem)?;
+                        elem
+                    }
+                };
+                let from = quote! {#path::<[#elem]>::from};
+                e.shared(ptr, unique, path, false, |e| {
+                    e.append(|out| out.extend(from));
+                    e.open(Delimiter::Parenthesis);
+                    e.open(Delimiter::Bracket);
+                    e.items(self.iter());
+                    e.close();
+                    e.close();
+                })
+            }
+
+            fn try_to_type_tokens_with(
+                ctx: &QuineContext,
+                out: &mut TokenStream,
+            ) -> Result<(), QuineError> {
+                ctx.append_std_path($item, out);
+                let mut elem = TokenStream::new();
+                T::try_to_type_tokens_with(ctx, &mut elem)?;
+                out.extend(quote! {<[#elem]>});
+                Ok(())
+            }
+        }
+
+        impl<T: Quine> Quine for $weak<T> {
+            fn try_to_ctor_tokens_with(
+                &self,
+                ctx: &QuineContext,
+                out: &mut TokenStream,
+            ) -> Result<(), QuineError> {
+                Emitter::run(self, ctx, out)
+            }
+
+            fn emit<'a>(&'a self, e: &mut Emitter<'a>) -> Result<(), QuineError> {
+                e.non_const(std::any::type_name::<Self>())?;
+                let ptr = (self.strong_count() > 0).then(|| self.as_ptr().cast::<()>());
+                let ctx = e.ctx();
+                let weak_path = std_ctor_path(ctx, $weak_item, &[T::try_to_type_tokens_with])?;
+                e.weak(ptr, std_path(ctx, $item), weak_path)
+            }
+
+            fn try_to_type_tokens_with(
+                ctx: &QuineContext,
+                out: &mut TokenStream,
+            ) -> Result<(), QuineError> {
+                append_std_type(ctx, $weak_item, &[T::try_to_type_tokens_with], out)
+            }
+        }
+    };
+}
+
+derive_shared!(Rc, RcWeak, StdItem::Rc, StdItem::RcWeak);
+derive_shared!(Arc, ArcWeak, StdItem::Arc, StdItem::ArcWeak);
+
+impl<T: Quine> Quine for RefCell<T> {
+    fn try_to_ctor_tokens_with(
+        &self,
+        ctx: &QuineContext,
+        out: &mut TokenStream,
+    ) -> Result<(), QuineError> {
+        Emitter::run(self, ctx, out)
+    }
+
+    fn emit<'a>(&'a self, e: &mut Emitter<'a>) -> Result<(), QuineError> {
+        let value = self
+            .try_borrow()
+            .map_err(|_| QuineError::custom("RefCell is mutably borrowed"))?;
+        let ctx = e.ctx();
+        e.try_append(|out| {
+            ctx.append_std_path(StdItem::RefCell, out);
+            append_turbofish(ctx, out, &[T::try_to_type_tokens_with])?;
+            append_segment(out, "new");
+            Ok(())
+        })?;
+        e.open(Delimiter::Parenthesis);
+        e.borrowed(&*value)?;
+        e.close();
+        Ok(())
+    }
+
+    fn try_to_type_tokens_with(
+        ctx: &QuineContext,
+        out: &mut TokenStream,
+    ) -> Result<(), QuineError> {
+        append_std_type(ctx, StdItem::RefCell, &[T::try_to_type_tokens_with], out)
     }
 }
 
 impl<T: Quine> Quine for Option<T> {
-    fn ctor_tokens(&self) -> TokenStream {
+    fn try_to_ctor_tokens_with(
+        &self,
+        ctx: &QuineContext,
+        out: &mut TokenStream,
+    ) -> Result<(), QuineError> {
+        Emitter::run(self, ctx, out)
+    }
+
+    fn emit<'a>(&'a self, e: &mut Emitter<'a>) -> Result<(), QuineError> {
+        let ctx = e.ctx();
+        let args: &[TypeTokensFn] = &[T::try_to_type_tokens_with];
         match self {
             Some(value) => {
-                let inner = value.ctor_tokens();
-                quote! {Some(#inner)}
+                e.try_append(|out| {
+                    ctx.append_std_path(StdItem::Some, out);
+                    append_turbofish(ctx, out, args)
+                })?;
+                e.open(Delimiter::Parenthesis);
+                e.value(value);
+                e.close();
             }
-            None => quote! {None},
+            None => e.try_append(|out| {
+                ctx.append_std_path(StdItem::None, out);
+                append_turbofish(ctx, out, args)
+            })?,
+        }
+        Ok(())
+    }
+
+    fn try_to_type_tokens_with(
+        ctx: &QuineContext,
+        out: &mut TokenStream,
+    ) -> Result<(), QuineError> {
+        append_std_type(ctx, StdItem::Option, &[T::try_to_type_tokens_with], out)
+    }
+
+    fn quine_cmp(&self, other: &Self) -> Option<Ordering> {
+        match (self, other) {
+            (Some(a), Some(b)) => a.quine_cmp(b),
+            (a, b) => Some(a.is_some().cmp(&b.is_some())),
         }
     }
 }
 
 impl<T: Quine, const N: usize> Quine for [T; N] {
-    fn ctor_tokens(&self) -> TokenStream {
-        let elements = self.iter().map(|elem| elem.ctor_tokens());
-        quote! {[#(#elements),*]}
+    fn try_to_ctor_tokens_with(
+        &self,
+        ctx: &QuineContext,
+        out: &mut TokenStream,
+    ) -> Result<(), QuineError> {
+        Emitter::run(self, ctx, out)
+    }
+
+    fn emit<'a>(&'a self, e: &mut Emitter<'a>) -> Result<(), QuineError> {
+        e.open(Delimiter::Bracket);
+        e.items(self);
+        e.close();
+        Ok(())
+    }
+
+    fn try_to_type_tokens_with(
+        ctx: &QuineContext,
+        out: &mut TokenStream,
+    ) -> Result<(), QuineError> {
+        let mut elem = TokenStream::new();
+        T::try_to_type_tokens_with(ctx, &mut elem)?;
+        let len = proc_macro2::Literal::usize_unsuffixed(N);
+        out.extend(quote! {[#elem; #len]});
+        Ok(())
+    }
+
+    fn quine_cmp(&self, other: &Self) -> Option<Ordering> {
+        cmp_items(self, other)
+    }
+}
+
+impl<T: Quine> Quine for Vec<T> {
+    fn try_to_ctor_tokens_with(
+        &self,
+        ctx: &QuineContext,
+        out: &mut TokenStream,
+    ) -> Result<(), QuineError> {
+        Emitter::run(self, ctx, out)
+    }
+
+    fn emit<'a>(&'a self, e: &mut Emitter<'a>) -> Result<(), QuineError> {
+        let ctx = e.ctx();
+        // `vec!` cannot be given type arguments, nor be emitted in const form
+        match (ctx.sequences(), ctx.type_args()) {
+            (SequenceStyle::Macro, TypeArgs::Inferred) if !e.is_const() => {
+                e.non_const(std::any::type_name::<Self>())?;
+                e.append(|out| ctx.append_std_path(StdItem::VecMacro, out));
+                e.open(Delimiter::Bracket);
+                e.items(self);
+                e.close();
+                Ok(())
+            }
+            _ => emit_from_array::<Self>(e, StdItem::Vec, &[T::try_to_type_tokens_with], |e| {
+                e.items(self);
+                Ok(())
+            }),
+        }
+    }
+
+    fn try_to_type_tokens_with(
+        ctx: &QuineContext,
+        out: &mut TokenStream,
+    ) -> Result<(), QuineError> {
+        append_std_type(ctx, StdItem::Vec, &[T::try_to_type_tokens_with], out)
+    }
+
+    fn quine_cmp(&self, other: &Self) -> Option<Ordering> {
+        cmp_items(self, other)
+    }
+}
+
+impl<T: Quine> Quine for VecDeque<T> {
+    fn try_to_ctor_tokens_with(
+        &self,
+        ctx: &QuineContext,
+        out: &mut TokenStream,
+    ) -> Result<(), QuineError> {
+        Emitter::run(self, ctx, out)
+    }
+
+    fn emit<'a>(&'a self, e: &mut Emitter<'a>) -> Result<(), QuineError> {
+        emit_from_array::<Self>(e, StdItem::VecDeque, &[T::try_to_type_tokens_with], |e| {
+            e.items(self);
+            Ok(())
+        })
+    }
+
+    fn try_to_type_tokens_with(
+        ctx: &QuineContext,
+        out: &mut TokenStream,
+    ) -> Result<(), QuineError> {
+        append_std_type(ctx, StdItem::VecDeque, &[T::try_to_type_tokens_with], out)
+    }
+
+    fn quine_cmp(&self, other: &Self) -> Option<Ordering> {
+        cmp_items(self, other)
+    }
+}
+
+impl<T: Quine> Quine for HashSet<T> {
+    fn try_to_ctor_tokens_with(
+        &self,
+        ctx: &QuineContext,
+        out: &mut TokenStream,
+    ) -> Result<(), QuineError> {
+        Emitter::run(self, ctx, out)
+    }
+
+    fn emit<'a>(&'a self, e: &mut Emitter<'a>) -> Result<(), QuineError> {
+        let ctx = e.ctx();
+        emit_from_array::<Self>(e, StdItem::HashSet, &[T::try_to_type_tokens_with], |e| {
+            match ctx.map_order() {
+                MapOrder::Iteration => e.items(self),
+                MapOrder::Sorted => e.items(sorted_entries(self, |item| *item, ctx)?),
+            }
+            Ok(())
+        })
+    }
+
+    fn try_to_type_tokens_with(
+        ctx: &QuineContext,
+        out: &mut TokenStream,
+    ) -> Result<(), QuineError> {
+        append_std_type(ctx, StdItem::HashSet, &[T::try_to_type_tokens_with], out)
+    }
+}
+
+impl<K: Quine, V: Quine> Quine for HashMap<K, V> {
+    fn try_to_ctor_tokens_with(
+        &self,
+        ctx: &QuineContext,
+        out: &mut TokenStream,
+    ) -> Result<(), QuineError> {
+        Emitter::run(self, ctx, out)
+    }
+
+    fn emit<'a>(&'a self, e: &mut Emitter<'a>) -> Result<(), QuineError> {
+        let ctx = e.ctx();
+        let args: &[TypeTokensFn] = &[K::try_to_type_tokens_with, V::try_to_type_tokens_with];
+        emit_from_array::<Self>(e, StdItem::HashMap, args, |e| {
+            match ctx.map_order() {
+                MapOrder::Iteration => e.entries(self),
+                MapOrder::Sorted => e.entries(sorted_entries(self, |(key, _)| *key, ctx)?),
+            }
+            Ok(())
+        })
+    }
+
+    fn try_to_type_tokens_with(
+        ctx: &QuineContext,
+        out: &mut TokenStream,
+    ) -> Result<(), QuineError> {
+        let args: &[TypeTokensFn] = &[K::try_to_type_tokens_with, V::try_to_type_tokens_with];
+        append_std_type(ctx, StdItem::HashMap, args, out)
+    }
+}
+
+impl<K: Quine, V: Quine> Quine for BTreeMap<K, V> {
+    fn try_to_ctor_tokens_with(
+        &self,
+        ctx: &QuineContext,
+        out: &mut TokenStream,
+    ) -> Result<(), QuineError> {
+        Emitter::run(self, ctx, out)
+    }
+
+    fn emit<'a>(&'a self, e: &mut Emitter<'a>) -> Result<(), QuineError> {
+        let args: &[TypeTokensFn] = &[K::try_to_type_tokens_with, V::try_to_type_tokens_with];
+        emit_from_array::<Self>(e, StdItem::BTreeMap, args, |e| {
+            e.entries(self);
+            Ok(())
+        })
+    }
+
+    fn try_to_type_tokens_with(
+        ctx: &QuineContext,
+        out: &mut TokenStream,
+    ) -> Result<(), QuineError> {
+        let args: &[TypeTokensFn] = &[K::try_to_type_tokens_with, V::try_to_type_tokens_with];
+        append_std_type(ctx, StdItem::BTreeMap, args, out)
+    }
+}
+
+impl Quine for f32 {
+    fn try_to_ctor_tokens_with(
+        &self,
+        _ctx: &QuineContext,
+        out: &mut TokenStream,
+    ) -> Result<(), QuineError> {
+        if !self.is_finite() {
+            return Err(QuineError::non_finite_float(self));
+        }
+        self.to_tokens(out);
+        Ok(())
+    }
+
+    /// A literal, which is `const`
+    fn emit<'a>(&'a self, e: &mut Emitter<'a>) -> Result<(), QuineError> {
+        let ctx = e.ctx();
+        e.try_append(|out| self.try_to_ctor_tokens_with(ctx, out))
+    }
+
+    fn try_to_type_tokens_with(
+        _ctx: &QuineContext,
+        out: &mut TokenStream,
+    ) -> Result<(), QuineError> {
+        append_ident(out, "f32");
+        Ok(())
+    }
+}
+
+impl Quine for f64 {
+    fn try_to_ctor_tokens_with(
+        &self,
+        _ctx: &QuineContext,
+        out: &mut TokenStream,
+    ) -> Result<(), QuineError> {
+        if !self.is_finite() {
+            return Err(QuineError::non_finite_float(self));
+        }
+        self.to_tokens(out);
+        Ok(())
+    }
+
+    /// A literal, which is `const`
+    fn emit<'a>(&'a self, e: &mut Emitter<'a>) -> Result<(), QuineError> {
+        let ctx = e.ctx();
+        e.try_append(|out| self.try_to_ctor_tokens_with(ctx, out))
+    }
+
+    fn try_to_type_tokens_with(
+        _ctx: &QuineContext,
+        out: &mut TokenStream,
+    ) -> Result<(), QuineError> {
+        append_ident(out, "f64");
+        Ok(())
     }
 }
 
 derive_primitive!(
-    i8, i16, i32, i64, i128, isize, u8, u16, u32, u64, u128, usize, f32, f64, bool, char, &str
+    ord: i8, i16, i32, i64, i128, isize, u8, u16, u32, u64, u128, usize, bool, char, &str
 );
 
-derive_trivial!(String);
-
 derive_tuple_all!(A B C D E F G H I J K);
 
-derive_iterable!(Vec<T>);
-derive_iterable!(VecDeque<T>);
-derive_iterable!(HashSet<T>);
-derive_iterable!(HashMap<K, V>);
-derive_iterable!(BTreeMap<K, V>);
-
 mod test {
     use super::*;
     #[allow(unused)]
     use crate::Quine;
     #[allow(unused)]
+    use crate::{
+        CratePath, Cycles, Dedup, DedupReport, Nesting, QuineErrorKind, QuineType, StdPaths,
+        TypePaths, resolve_dollar_crate,
+    };
+    #[allow(unused)]
+    use quote::quote;
+    #[allow(unused)]
     use std::fmt::Display;
     #[allow(unused)]
     use std::fmt::Formatter;
@@ -75,7 +953,7 @@ mod test {
 
         #[allow(dead_code)]
         #[derive(Quine)]
-        #[path_prefix(some_module)]
+        #[polyquine(prefix = some_module)]
         pub struct Custom {
             pub value: i32,
         }
@@ -89,331 +967,1745 @@ mod test {
     #[test]
     fn test_vec() {
         let vec = Vec::from([1i32, 2i32, 3i32]);
-        assert_ts_eq(&vec.ctor_tokens(), &quote! {Vec::from([1i32, 2i32, 3i32])});
-    }
-
-    #[test]
-    fn test_tuple() {
-        let tuple = (1i32, true);
-        assert_ts_eq(&tuple.ctor_tokens(), &quote! {(1i32, true)});
+        assert_ts_eq(
+            &vec.ctor_tokens(),
+            &quote! {::std::vec::Vec::from([1i32, 2i32, 3i32])},
+        );
     }
 
     #[test]
-    fn test_hashmap() {
-        let map = HashMap::from([(1i32, "one")]);
-        assert_ts_eq(&map.ctor_tokens(), &quote! {HashMap::from([(1i32, "one")])});
-    }
+    fn test_to_ctor_tokens() {
+        #[derive(Quine)]
+        struct Raw {
+            r#type: Vec<(i32, &'static str)>,
+        }
 
-    #[test]
-    fn test_box() {
-        let boxed = Box::new(String::from("hello, world!"));
+        let mut out = quote! {let value =};
+        Raw {
+            r#type: vec![(1i32, "one")],
+        }
+        .to_ctor_tokens(&mut out);
         assert_ts_eq(
-            &boxed.ctor_tokens(),
-            &quote! {Box::new(String::from("hello, world!"))},
+            &out,
+            &quote! {let value = polyquine::quine::test::Raw {
+                r#type: ::std::vec::Vec::from([(1i32, "one")])
+            }},
         );
     }
 
     #[test]
-    fn test_slice() {
-        let slice = [1i32, 2i32, 3i32];
-        assert_ts_eq(&slice.ctor_tokens(), &quote! {[1i32, 2i32, 3i32]});
+    fn test_deep_nesting() {
+        const DEPTH: usize = 1_000_000;
+
+        #[derive(Quine)]
+        struct Node {
+            value: i32,
+            next: Option<Box<Node>>,
+        }
+
+        // The derived `Drop` would recurse once per node
+        impl Drop for Node {
+            fn drop(&mut self) {
+                let mut next = self.next.take();
+                while let Some(mut node) = next {
+                    next = node.next.take();
+                }
+            }
+        }
+
+        let mut list = None;
+        for value in (0..DEPTH as i32).rev() {
+            list = Some(Box::new(Node { value, next: list }));
+        }
+
+        // Unqualified paths keep the output small
+        let ctx = QuineContext::new()
+            .with_type_paths(TypePaths::Unqualified)
+            .with_std_paths(StdPaths::Unqualified);
+        let tokens = list.ctor_tokens_with(&ctx);
+
+        // Walk down `Some(Box::new(Node { value: N, next: .. }))` without recursing,
+        // since formatting the tokens would
+        let last_group = |tokens: TokenStream| match tokens.into_iter().last() {
+            Some(proc_macro2::TokenTree::Group(group)) => Some(group),
+            _ => None,
+        };
+        let mut tokens = tokens;
+        let mut depth = 0;
+        while let Some(some) = last_group(tokens.clone()) {
+            let boxed = last_group(some.stream()).expect("Some(Box::new(..))");
+            let node = last_group(boxed.stream()).expect("Box::new(Node { .. })");
+            assert_eq!(node.delimiter(), Delimiter::Brace);
+            let value = node.stream().into_iter().nth(2).expect("value: N");
+            assert_eq!(value.to_string(), format!("{depth}i32"));
+
+            depth += 1;
+            tokens = node.stream();
+        }
+        assert_eq!(depth, DEPTH);
     }
 
     #[test]
-    fn test_custom_module_prefix() {
-        let value = custom_module::Custom { value: 42i32 };
+    fn test_flatten() {
+        #[derive(Quine)]
+        struct Node {
+            value: i32,
+            next: Option<Box<Node>>,
+        }
+
+        let list = Some(Box::new(Node {
+            value: 0i32,
+            next: Some(Box::new(Node {
+                value: 1i32,
+                next: None,
+            })),
+        }));
+        let ctx = QuineContext::new()
+            .with_type_paths(TypePaths::Unqualified)
+            .with_std_paths(StdPaths::Unqualified)
+            .with_nesting(Nesting::Flatten { max_depth: 2 });
+        // Temporaries are numbered in the order they are found, and bound once their value is complete
+        assert_ts_eq(
+            &list.ctor_tokens_with(&ctx),
+            &quote! {{
+                let __q_2 = 1i32;
+                let __q_3 = None;
+                let __q_1 = Box::new(Node { value: __q_2, next: __q_3 });
+                let __q_0 = Node { value: 0i32, next: Some(__q_1) };
+                Some(Box::new(__q_0))
+            }},
+        );
+
+        // Shallow values are left alone
+        let ctx = ctx.with_nesting(Nesting::Flatten { max_depth: 3 });
+        assert_ts_eq(&Some(1i32).ctor_tokens_with(&ctx), &quote! {Some(1i32)});
+    }
+
+    #[test]
+    fn test_dedup() {
+        #[derive(Quine, Clone)]
+        struct Metadata {
+            src: String,
+        }
+
+        let ctx = QuineContext::new()
+            .with_type_paths(TypePaths::Unqualified)
+            .with_std_paths(StdPaths::Unqualified)
+            .with_dedup(Dedup::Subtrees);
+        let metadata = vec![
+            Metadata {
+                src: String::from("shared"),
+            };
+            3
+        ];
+        let mut tokens = TokenStream::new();
+        let report = Emitter::run_with_report(&metadata, &ctx, &mut tokens).unwrap();
+        assert_ts_eq(
+            &tokens,
+            &quote! {{
+                let __q_0 = Metadata { src: String::from("shared") };
+                Vec::from([__q_0.clone(), __q_0.clone(), __q_0.clone()])
+            }},
+        );
+        assert_eq!(
+            report,
+            DedupReport {
+                tokens_before: 38,
+                tokens_after: 35,
+                shared: 1,
+            }
+        );
+
+        // Not worth it for short subtrees
+        assert_ts_eq(
+            &vec![1i32, 1i32].ctor_tokens_with(&ctx),
+            &quote! {Vec::from([1i32, 1i32])},
+        );
+
+        // The same tokens for values of different types
+        #[derive(Quine)]
+        struct Two {
+            a: Vec<i32>,
+            b: Vec<String>,
+        }
+
+        let two = Two {
+            a: Vec::new(),
+            b: Vec::new(),
+        };
+        let ctx = QuineContext::new()
+            .with_type_paths(TypePaths::Unqualified)
+            .with_dedup(Dedup::Subtrees);
+        assert_ts_eq(
+            &two.ctor_tokens_with(&ctx),
+            &quote! {Two { a: ::std::vec::Vec::from([]), b: ::std::vec::Vec::from([]) }},
+        );
+    }
+
+    #[test]
+    fn test_shared() {
+        let ctx = QuineContext::new().with_std_paths(StdPaths::Unqualified);
+
+        // Only referred to once: no need for a `let`
+        assert_ts_eq(
+            &Rc::new(1i32).ctor_tokens_with(&ctx),
+            &quote! {Rc::new(1i32)},
+        );
+        assert_ts_eq(
+            &Arc::<str>::from("name").ctor_tokens_with(&ctx),
+            &quote! {Arc::<str>::from("name")},
+        );
+
+        let name = Rc::<str>::from("name");
+        let names = Arc::<[Rc<str>]>::from([name.clone(), Rc::from("other"), name]);
+        let graph = vec![names.clone(), Arc::from([]), names];
+        assert_ts_eq(
+            &graph.ctor_tokens_with(&ctx),
+            &quote! {{
+                let __rc_1 = Rc::<str>::from("name");
+                let __rc_0 = Arc::<[_]>::from([Rc::clone(&__rc_1), Rc::<str>::from("other"), Rc::clone(&__rc_1)]);
+                Vec::from([Arc::clone(&__rc_0), Arc::<[_]>::from([]), Arc::clone(&__rc_0)])
+            }},
+        );
+
+        // The `let`s are kept in order with the deduplicated subtrees they refer to, and the ones referring to them
+        let leaf = Rc::new(String::from("leaf"));
+        let shared = vec![
+            (Some(leaf.clone()), String::from("shared")),
+            (Some(leaf), String::from("shared")),
+        ];
+        assert_ts_eq(
+            &shared.ctor_tokens_with(&ctx.with_dedup(Dedup::Subtrees)),
+            &quote! {{
+                let __rc_0 = Rc::new(String::from("leaf"));
+                let __q_0 = (Some(Rc::clone(&__rc_0)), String::from("shared"));
+                Vec::from([__q_0.clone(), __q_0.clone()])
+            }},
+        );
+    }
+
+    #[test]
+    fn test_cycles() {
+        #[derive(Quine)]
+        struct TreeNode {
+            name: String,
+            parent: RcWeak<RefCell<TreeNode>>,
+            children: Vec<Rc<RefCell<TreeNode>>>,
+        }
+
+        let node = |name: &str, parent: &Rc<RefCell<TreeNode>>| {
+            let node = Rc::new(RefCell::new(TreeNode {
+                name: String::from(name),
+                parent: Rc::downgrade(parent),
+                children: vec![],
+            }));
+            parent.borrow_mut().children.push(node.clone());
+            node
+        };
+        let root = Rc::new(RefCell::new(TreeNode {
+            name: String::from("root"),
+            parent: RcWeak::new(),
+            children: vec![],
+        }));
+        let a = node("a", &root);
+        node("g", &a);
+        node("b", &root);
+
+        let ctx = QuineContext::new()
+            .with_type_paths(TypePaths::Unqualified)
+            .with_std_paths(StdPaths::Unqualified);
+        let err = root.try_ctor_tokens_with(&ctx).unwrap_err();
+        assert_eq!(err.kind(), &QuineErrorKind::Cycle { weak: true });
+        assert_eq!(err.path().to_string(), "TreeNode.children[0].parent");
+
+        // `__rc_1` refers to `__weak_0`, so its `let` goes inside the closure of `__rc_0`
+        let ctx = ctx.with_cycles(Cycles::NewCyclic);
+        assert_ts_eq(
+            &root.ctor_tokens_with(&ctx),
+            &quote! {{
+                let __rc_0 = Rc::new_cyclic(|__weak_0| {
+                    let __rc_1 = Rc::new_cyclic(|__weak_1| RefCell::new(TreeNode {
+                        name: String::from("a"),
+                        parent: Weak::clone(__weak_0),
+                        children: Vec::from([Rc::new(RefCell::new(TreeNode {
+                            name: String::from("g"),
+                            parent: Weak::clone(__weak_1),
+                            children: Vec::from([])
+                        }))])
+                    }));
+                    RefCell::new(TreeNode {
+                        name: String::from("root"),
+                        parent: Weak::new(),
+                        children: Vec::from([
+                            Rc::clone(&__rc_1),
+                            Rc::new(RefCell::new(TreeNode {
+                                name: String::from("b"),
+                                parent: Weak::clone(__weak_0),
+                                children: Vec::from([])
+                            }))
+                        ])
+                    })
+                });
+                Rc::clone(&__rc_0)
+            }},
+        );
+
+        // Deduplicated subtrees that refer to `__weak_0` stay inside its closure
+        let tokens = root.ctor_tokens_with(&ctx.clone().with_dedup(Dedup::Subtrees));
+        let tokens = tokens.to_string();
+        assert!(tokens.starts_with("{ let __rc_0 = Rc :: new_cyclic (| __weak_0 |"));
+        assert_eq!(tokens.matches("Weak :: clone (__weak_0)").count(), 2);
+
+        // A `Weak` to an allocation found before it
+        let shared = Rc::new(1i32);
+        assert_ts_eq(
+            &(shared.clone(), Rc::downgrade(&shared)).ctor_tokens_with(&ctx),
+            &quote! {{
+                let __rc_0 = Rc::new(1i32);
+                (Rc::clone(&__rc_0), Rc::downgrade(&__rc_0))
+            }},
+        );
+        // A `Weak` to an allocation outside of the value
+        let err = a.try_ctor_tokens_with(&ctx).unwrap_err();
+        assert_eq!(err.kind(), &QuineErrorKind::UnownedWeak);
+        assert_eq!(err.path().to_string(), "TreeNode.parent");
+
+        // A cycle of strong references cannot be rebuilt
+        #[derive(Quine)]
+        struct Link {
+            next: Option<Rc<RefCell<Link>>>,
+        }
+        let link = Rc::new(RefCell::new(Link { next: None }));
+        link.borrow_mut().next = Some(link.clone());
+        let err = link.try_ctor_tokens_with(&ctx).unwrap_err();
+        assert_eq!(err.kind(), &QuineErrorKind::Cycle { weak: false });
+        assert_eq!(err.path().to_string(), "Link.next");
+        link.borrow_mut().next = None;
+    }
+
+    #[test]
+    fn test_field_attrs() {
+        // Not `Quine`
+        struct Handle(u64);
+
+        fn handle_tokens(handle: &Handle) -> TokenStream {
+            let id = handle.0;
+            quote! {Handle::open(#id)}
+        }
+
+        #[derive(Quine)]
+        #[allow(dead_code)]
+        struct Session {
+            name: String,
+            #[polyquine(skip)]
+            hits: std::cell::Cell<usize>,
+            #[polyquine(skip, default = Vec::with_capacity(16))]
+            pending: Vec<Handle>,
+            #[polyquine(with = handle_tokens)]
+            handle: Handle,
+            #[polyquine(expr = "std::env::var(\"HOME\").ok()")]
+            home: Option<String>,
+        }
+
+        #[derive(Quine)]
+        #[allow(dead_code)]
+        enum Resource {
+            File(String, #[polyquine(with = handle_tokens)] Handle),
+            Cached {
+                key: String,
+                #[polyquine(skip, default = Rc::new(HashMap::new()))]
+                cache: Rc<HashMap<String, Handle>>,
+            },
+        }
+
+        let session = Session {
+            name: String::from("main"),
+            hits: std::cell::Cell::new(3),
+            pending: vec![Handle(1)],
+            handle: Handle(7),
+            home: None,
+        };
+        let ctx = QuineContext::new()
+            .with_type_paths(TypePaths::Unqualified)
+            .with_std_paths(StdPaths::Unqualified);
+        assert_ts_eq(
+            &session.ctor_tokens_with(&ctx),
+            &quote! {Session {
+                name: String::from("main"),
+                hits: ::core::default::Default::default(),
+                pending: Vec::with_capacity(16),
+                handle: Handle::open(7u64),
+                home: std::env::var("HOME").ok()
+            }},
+        );
+        assert_ts_eq(
+            &Resource::File(String::from("a.txt"), Handle(2)).ctor_tokens_with(&ctx),
+            &quote! {Resource::File(String::from("a.txt"), Handle::open(2u64))},
+        );
+        let cached = Resource::Cached {
+            key: String::from("k"),
+            cache: Rc::new(HashMap::from([(String::from("k"), Handle(3))])),
+        };
+        assert_ts_eq(
+            &cached.ctor_tokens_with(&ctx),
+            &quote! {Resource::Cached { key: String::from("k"), cache: Rc::new(HashMap::new()) }},
+        );
+    }
+
+    #[test]
+    fn test_container_ctor() {
+        mod range {
+            use crate::Quine;
+
+            #[derive(Quine)]
+            #[polyquine(ctor = Range::new, args(start, end))]
+            #[allow(dead_code)]
+            pub struct Range {
+                start: u32,
+                end: u32,
+                len: u32,
+            }
+
+            impl Range {
+                pub fn new(start: u32, end: u32) -> Self {
+                    assert!(start <= end);
+                    Range {
+                        start,
+                        end,
+                        len: end - start,
+                    }
+                }
+            }
+
+            #[derive(Quine)]
+            #[polyquine(ctor = Self::from_parts)]
+            #[allow(dead_code)]
+            pub struct Pair(pub i32, #[polyquine(expr = "\"b\"")] pub &'static str);
+
+            impl Pair {
+                #[allow(dead_code)]
+                pub fn from_parts(first: i32, second: &'static str) -> Self {
+                    Pair(first, second)
+                }
+            }
+
+            #[derive(Quine)]
+            #[polyquine(ctor = ordered)]
+            pub struct Ordered(pub u8, pub u8);
+
+            #[allow(dead_code)]
+            pub fn ordered(a: u8, b: u8) -> Ordered {
+                Ordered(a.min(b), a.max(b))
+            }
+        }
+
+        mod secret {
+            use crate::Quine;
+
+            #[derive(Quine)]
+            #[polyquine(hidden_ctor)]
+            #[allow(dead_code)]
+            pub struct Secret<T> {
+                key: T,
+                #[polyquine(skip)]
+                uses: u32,
+            }
+
+            impl<T> Secret<T> {
+                pub fn new(key: T) -> Self {
+                    Secret::__polyquine_new(key, 0)
+                }
+
+                pub fn key(&self) -> &T {
+                    &self.key
+                }
+            }
+
+            #[derive(Quine)]
+            #[polyquine(hidden_ctor)]
+            pub struct Unit;
+        }
+
+        // Converted from a `Vec` of the items, in order
+        #[derive(Quine)]
+        #[polyquine(via = Vec<i32>)]
+        struct Sorted(Vec<i32>);
+
+        impl From<&Sorted> for Vec<i32> {
+            fn from(sorted: &Sorted) -> Self {
+                sorted.0.clone()
+            }
+        }
+
+        let ctx = QuineContext::new()
+            .with_type_paths(TypePaths::Unqualified)
+            .with_std_paths(StdPaths::Unqualified);
+        assert_ts_eq(
+            &range::Range::new(2, 5).ctor_tokens_with(&ctx),
+            &quote! {Range::new(2u32, 5u32)},
+        );
+        assert_ts_eq(
+            &range::Range::new(2, 5).ctor_tokens(),
+            &quote! {polyquine::quine::test::range::Range::new(2u32, 5u32)},
+        );
+        assert_ts_eq(
+            &range::Pair(1, "a").ctor_tokens_with(&ctx),
+            &quote! {Pair::from_parts(1i32, "b")},
+        );
+        assert_ts_eq(
+            &range::Ordered(1, 2).ctor_tokens_with(&ctx),
+            &quote! {ordered(1u8, 2u8)},
+        );
+        let secret = secret::Secret::new(String::from("k"));
+        assert_eq!(secret.key(), "k");
+        assert_ts_eq(
+            &secret.ctor_tokens_with(&ctx),
+            &quote! {Secret::__polyquine_new(String::from("k"), ::core::default::Default::default())},
+        );
+        assert_ts_eq(
+            &secret::Unit.ctor_tokens_with(&ctx),
+            &quote! {Unit::__polyquine_new()},
+        );
+        assert_ts_eq(
+            &Sorted(vec![1, 2]).ctor_tokens_with(&ctx),
+            &quote! {Sorted::from(Vec::from([1i32, 2i32]))},
+        );
+    }
+
+    #[test]
+    fn test_union() {
+        // SAFETY: `Bits` is only ever built from `int`
+        #[derive(Quine)]
+        #[polyquine(unsafe_active = int)]
+        #[allow(dead_code)]
+        union Bits {
+            int: u32,
+            float: f32,
+        }
+
+        fn float_tokens(float: &Float) -> TokenStream {
+            // SAFETY: `Float` is only ever built from `float`
+            let float = unsafe { float.float };
+            quote! {Float { float: #float }}
+        }
+
+        #[derive(Quine)]
+        #[polyquine(with = float_tokens)]
+        #[allow(dead_code)]
+        union Float {
+            float: f32,
+            int: u32,
+        }
+
+        let ctx = QuineContext::new().with_type_paths(TypePaths::Unqualified);
+        assert_ts_eq(
+            &Bits { int: 7 }.ctor_tokens_with(&ctx),
+            &quote! {Bits { int: 7u32 }},
+        );
+        assert_ts_eq(
+            &Float { float: 0.5 }.ctor_tokens_with(&ctx),
+            &quote! {Float { float: 0.5f32 }},
+        );
+    }
+
+    #[test]
+    fn test_tuple() {
+        let tuple = (1i32, true);
+        assert_ts_eq(&tuple.ctor_tokens(), &quote! {(1i32, true)});
+    }
+
+    #[test]
+    fn test_hashmap() {
+        let map = HashMap::from([(1i32, "one")]);
+        assert_ts_eq(
+            &map.ctor_tokens(),
+            &quote! {::std::collections::HashMap::from([(1i32, "one")])},
+        );
+    }
+
+    #[test]
+    fn test_derive_iterable() {
+        // Nothing in scope, so that the macro only relies on `$crate`
+        mod registry {
+            pub struct Registry<K, V>(pub std::collections::BTreeMap<K, V>);
+
+            impl<K, V> Registry<K, V> {
+                pub fn iter(&self) -> std::collections::btree_map::Iter<'_, K, V> {
+                    self.0.iter()
+                }
+            }
+
+            pub struct Names(pub Vec<String>);
+
+            impl Names {
+                pub fn iter(&self) -> std::slice::Iter<'_, String> {
+                    self.0.iter()
+                }
+            }
+
+            crate::derive_iterable!(Registry<K, V>);
+            crate::derive_iterable!(Names);
+        }
+        use registry::{Names, Registry};
+
+        let ctx = QuineContext::new().with_std_paths(StdPaths::Unqualified);
+        let registry = Registry(BTreeMap::from([(2u8, 'b'), (1u8, 'a')]));
+        assert_ts_eq(
+            &registry.ctor_tokens_with(&ctx),
+            &quote! {Registry::from([(1u8, 'a'), (2u8, 'b')])},
+        );
+        let names = Names(vec!["a".to_string()]);
+        assert_ts_eq(
+            &names.ctor_tokens_with(&ctx),
+            &quote! {Names::from([String::from("a")])},
+        );
+    }
+
+    #[test]
+    fn test_map_order() {
+        let ctx = QuineContext::new().with_std_paths(StdPaths::Unqualified);
+
+        // Sorted with `Ord`, not by the tokens, which would put `10i32` first
+        let set = HashSet::from([10i32, 9, -1]);
+        assert_ts_eq(
+            &set.ctor_tokens_with(&ctx),
+            &quote! {HashSet::from([-1i32, 9i32, 10i32])},
+        );
+        let map = HashMap::from([
+            (Some(String::from("b")), 1i32),
+            (None, 2),
+            (Some(String::from("a")), 3),
+        ]);
+        assert_ts_eq(
+            &map.ctor_tokens_with(&ctx),
+            &quote! {HashMap::from([
+                (None, 2i32),
+                (Some(String::from("a")), 3i32),
+                (Some(String::from("b")), 1i32)
+            ])},
+        );
+
+        // Keys without an order fall back to their tokens
+        #[derive(Quine, PartialEq, Eq, Hash)]
+        struct Key(i32);
+        let map = HashMap::from([(Key(2), "b"), (Key(10), "c"), (Key(1), "a")]);
+        assert_ts_eq(
+            &map.ctor_tokens_with(&ctx.clone().with_type_paths(TypePaths::Unqualified)),
+            &quote! {HashMap::from([(Key(10i32), "c"), (Key(1i32), "a"), (Key(2i32), "b")])},
+        );
+
+        // Only the keys are emitted on their own, so the values may refer to the rest of the value
+        #[derive(Quine)]
+        struct Graph {
+            root: Rc<u8>,
+            index: HashMap<Key, RcWeak<u8>>,
+        }
+        let root = Rc::new(1u8);
+        let graph = Graph {
+            index: HashMap::from([(Key(1), Rc::downgrade(&root))]),
+            root,
+        };
+        assert_ts_eq(
+            &graph.ctor_tokens_with(&ctx.clone().with_type_paths(TypePaths::Unqualified)),
+            &quote! {{
+                let __rc_0 = Rc::new(1u8);
+                Graph {
+                    root: Rc::clone(&__rc_0),
+                    index: HashMap::from([(Key(1i32), Rc::downgrade(&__rc_0))])
+                }
+            }},
+        );
+
+        // Some keys are ordered and some are not
+        let mixed = HashSet::from([Some(Key(2)), None, Some(Key(10))]);
+        assert_ts_eq(
+            &mixed.ctor_tokens_with(&ctx.clone().with_type_paths(TypePaths::Unqualified)),
+            &quote! {HashSet::from([None, Some(Key(10i32)), Some(Key(2i32))])},
+        );
+
+        // Tuples are ordered field by field, not by their tokens, which would put `10u8` first
+        let map = HashMap::from([((10u8, 'a'), 1u8), ((9, 'b'), 2), ((9, 'a'), 3)]);
+        assert_ts_eq(
+            &map.ctor_tokens_with(&ctx),
+            &quote! {HashMap::from([((9u8, 'a'), 3u8), ((9u8, 'b'), 2u8), ((10u8, 'a'), 1u8)])},
+        );
+        assert_eq!((1u8, Key(1)).quine_cmp(&(2u8, Key(1))), None);
+
+        // Keys that have no order and cannot be emitted on their own have no stable order
+        #[derive(Quine)]
+        struct Handle(RcWeak<u8>);
+        impl PartialEq for Handle {
+            fn eq(&self, other: &Self) -> bool {
+                self.0.ptr_eq(&other.0)
+            }
+        }
+        impl Eq for Handle {}
+        impl std::hash::Hash for Handle {
+            fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+                self.0.as_ptr().hash(state);
+            }
+        }
+        #[derive(Quine)]
+        struct Handles {
+            roots: Vec<Rc<u8>>,
+            handles: HashSet<Handle>,
+        }
+        let roots = vec![Rc::new(1u8), Rc::new(2u8)];
+        let handles = Handles {
+            handles: roots
+                .iter()
+                .map(|root| Handle(Rc::downgrade(root)))
+                .collect(),
+            roots,
+        };
+        let err = handles.try_ctor_tokens_with(&ctx).unwrap_err();
+        assert!(matches!(err.kind(), QuineErrorKind::UnsortableKey(_)));
+        assert!(
+            handles
+                .try_ctor_tokens_with(&ctx.clone().with_map_order(MapOrder::Iteration))
+                .is_ok()
+        );
+
+        let ctx = ctx.with_map_order(MapOrder::Iteration);
+        let tokens = set.ctor_tokens_with(&ctx).to_string();
+        assert!(
+            ["- 1i32", "9i32", "10i32"]
+                .iter()
+                .all(|item| tokens.contains(item))
+        );
+    }
+
+    #[test]
+    fn test_box() {
+        let boxed = Box::new(String::from("hello, world!"));
+        assert_ts_eq(
+            &boxed.ctor_tokens(),
+            &quote! {::std::boxed::Box::new(::std::string::String::from("hello, world!"))},
+        );
+    }
+
+    #[test]
+    fn test_slice() {
+        let slice = [1i32, 2i32, 3i32];
+        assert_ts_eq(&slice.ctor_tokens(), &quote! {[1i32, 2i32, 3i32]});
+    }
+
+    #[test]
+    fn test_custom_module_prefix() {
+        let value = custom_module::Custom { value: 42i32 };
+        assert_ts_eq(
+            &value.ctor_tokens(),
+            &quote! {some_module::Custom { value: 42i32 }},
+        );
+    }
+
+    #[test]
+    fn test_struct() {
+        #[derive(Quine)]
+        struct TestStruct {
+            a: i32,
+            b: bool,
+        }
+
+        let test_struct = TestStruct { a: 1i32, b: true };
+        assert_ts_eq(
+            &test_struct.ctor_tokens(),
+            &quote! {polyquine::quine::test::TestStruct { a: 1i32, b: true }},
+        );
+    }
+
+    #[test]
+    fn test_tuple_struct() {
+        #[derive(Quine)]
+        struct TestTupleStruct(i32, bool);
+
+        let test_tuple_struct = TestTupleStruct(1i32, true);
+        assert_ts_eq(
+            &test_tuple_struct.ctor_tokens(),
+            &quote! {polyquine::quine::test::TestTupleStruct(1i32, true)},
+        );
+    }
+
+    #[test]
+    fn test_unit_struct() {
+        #[derive(Quine)]
+        struct TestUnitStruct;
+
+        let test_unit_struct = TestUnitStruct {};
+        assert_ts_eq(
+            &test_unit_struct.ctor_tokens(),
+            &quote! {polyquine::quine::test::TestUnitStruct {}},
+        );
+    }
+
+    #[test]
+    fn test_nested_struct() {
+        #[derive(Quine)]
+        struct Node {
+            value: i32,
+            next: Option<Box<Node>>,
+        }
+
+        let node = Node {
+            value: 1i32,
+            next: Some(Box::new(Node {
+                value: 2i32,
+                next: None,
+            })),
+        };
+        assert_ts_eq(
+            &node.ctor_tokens(),
+            &quote! {polyquine::quine::test::Node { value: 1i32, next: ::core::option::Option::Some(::std::boxed::Box::new(polyquine::quine::test::Node { value: 2i32, next: ::core::option::Option::None })) }},
+        );
+    }
+
+    #[test]
+    fn test_enum() {
+        #[derive(Quine)]
+        enum TestEnum {
+            A,
+            B(i32),
+            C { name: String },
+        }
+
+        let a = TestEnum::A;
+        let b = TestEnum::B(1i32);
+        let c = TestEnum::C {
+            name: String::from("John"),
+        };
+
+        assert_ts_eq(
+            &a.ctor_tokens(),
+            &quote! {polyquine::quine::test::TestEnum::A},
+        );
+        assert_ts_eq(
+            &b.ctor_tokens(),
+            &quote! {polyquine::quine::test::TestEnum::B(1i32)},
+        );
+        assert_ts_eq(
+            &c.ctor_tokens(),
+            &quote! {polyquine::quine::test::TestEnum::C { name: ::std::string::String::from("John") }},
+        );
+    }
+
+    #[test]
+    fn test_ast() {
+        #[derive(Quine)]
+        struct Metadata {
+            src: String,
+        }
+
+        #[derive(Quine)]
+        enum Ast {
+            Num(Box<Metadata>, isize),
+            Mul(Box<Metadata>, Box<Ast>, Box<Ast>),
+            Sum(Box<Metadata>, Vec<Ast>),
+        }
+
+        let ast = Ast::Sum(
+            Box::new(Metadata {
+                src: String::from("1 + (2 * 3)"),
+            }),
+            vec![
+                Ast::Num(
+                    Box::new(Metadata {
+                        src: String::from("1"),
+                    }),
+                    1,
+                ),
+                Ast::Mul(
+                    Box::new(Metadata {
+                        src: String::from("2 * 3"),
+                    }),
+                    Box::new(Ast::Num(
+                        Box::new(Metadata {
+                            src: String::from("2"),
+                        }),
+                        2,
+                    )),
+                    Box::new(Ast::Num(
+                        Box::new(Metadata {
+                            src: String::from("3"),
+                        }),
+                        3,
+                    )),
+                ),
+            ],
+        );
+
+        assert_ts_eq(
+            &ast.ctor_tokens(),
+            &quote! {
+                polyquine::quine::test::Ast::Sum(
+                    ::std::boxed::Box::new(polyquine::quine::test::Metadata {
+                        src: ::std::string::String::from("1 + (2 * 3)")
+                    }),
+                    ::std::vec::Vec::from([
+                        polyquine::quine::test::Ast::Num(
+                            ::std::boxed::Box::new(polyquine::quine::test::Metadata {
+                                src: ::std::string::String::from("1")
+                            }),
+                            1isize
+                        ),
+                        polyquine::quine::test::Ast::Mul(
+                            ::std::boxed::Box::new(polyquine::quine::test::Metadata {
+                                src: ::std::string::String::from("2 * 3")
+                            }),
+                            ::std::boxed::Box::new(polyquine::quine::test::Ast::Num(
+                                ::std::boxed::Box::new(polyquine::quine::test::Metadata {
+                                    src: ::std::string::String::from("2")
+                                }),
+                                2isize
+                            )),
+                            ::std::boxed::Box::new(polyquine::quine::test::Ast::Num(
+                                ::std::boxed::Box::new(polyquine::quine::test::Metadata {
+                                    src: ::std::string::String::from("3")
+                                }),
+                                3isize
+                            ))
+                        )
+                    ])
+                )
+            },
+        );
+    }
+
+    #[test]
+    fn test_ustr() {
+        let u1 = Ustr::from("the quick brown fox");
+        assert_ts_eq(
+            &u1.ctor_tokens(),
+            &quote! {::ustr::Ustr::from("the quick brown fox")},
+        );
+    }
+
+    #[test]
+    fn test_struct_with_generic() {
+        #[derive(Quine)]
+        struct Test<T: Display> {
+            value: T,
+        }
+
+        let good: Test<String> = Test {
+            value: String::from("Hello World"),
+        };
+        assert_ts_eq(
+            &good.ctor_tokens(),
+            &quote! {
+                polyquine::quine::test::Test {
+                    value: ::std::string::String::from("Hello World")
+                }
+            },
+        );
+    }
+
+    #[test]
+    fn test_enum_with_generic() {
+        #[derive(Quine)]
+        enum TestEnum<T> {
+            A(T),
+        }
+
+        let a = TestEnum::A(String::from("Hello World"));
+        assert_ts_eq(
+            &a.ctor_tokens(),
+            &quote! {
+                polyquine::quine::test::TestEnum::A(::std::string::String::from("Hello World"))
+            },
+        );
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "Attempted to call ctor_tokens() on skipped enum variant TestEnum::B"
+    )]
+    fn test_skipped_variant() {
+        #[derive(Quine)]
+        enum TestEnum {
+            A,
+            #[polyquine(skip)]
+            B,
+        }
+
+        let a = TestEnum::A;
+        assert_ts_eq(
+            &a.ctor_tokens(),
+            &quote! {polyquine::quine::test::TestEnum::A},
+        );
+
+        let b = TestEnum::B;
+        let _ = b.ctor_tokens(); // This should panic
+    }
+
+    #[test]
+    fn test_try_skipped_variant() {
+        #[derive(Quine)]
+        enum TestEnum {
+            A,
+            #[polyquine(skip)]
+            B,
+        }
+
+        assert!(TestEnum::A.try_ctor_tokens().is_ok());
+
+        let err = TestEnum::B.try_ctor_tokens().unwrap_err();
+        assert_eq!(
+            err.kind(),
+            &QuineErrorKind::SkippedVariant {
+                ty: "TestEnum",
+                variant: "B"
+            }
+        );
+    }
+
+    #[test]
+    fn test_non_finite_float() {
+        assert_ts_eq(&1.5f64.ctor_tokens(), &quote! {1.5f64});
+
+        let err = f32::NAN.try_ctor_tokens().unwrap_err();
+        assert_eq!(
+            err.kind(),
+            &QuineErrorKind::NonFiniteFloat(String::from("NaN"))
+        );
+
+        let err = vec![1.0f64, f64::INFINITY].try_ctor_tokens().unwrap_err();
+        assert_eq!(
+            err.kind(),
+            &QuineErrorKind::NonFiniteFloat(String::from("inf"))
+        );
+    }
+
+    #[test]
+    fn test_error_path() {
+        #[derive(Quine)]
+        struct Metadata {
+            src: String,
+            weight: f64,
+        }
+
+        #[derive(Quine)]
+        enum Ast {
+            Num(Box<Metadata>, isize),
+            Mul(Box<Metadata>, Box<Ast>, Box<Ast>),
+            Sum(Box<Metadata>, Vec<Ast>),
+            #[polyquine(skip)]
+            Hole,
+        }
+
+        let meta = |src: &str, weight: f64| {
+            Box::new(Metadata {
+                src: String::from(src),
+                weight,
+            })
+        };
+
+        let ast = Ast::Sum(
+            meta("1 + 2 * _", 1.0),
+            vec![
+                Ast::Num(meta("1", 1.0), 1),
+                Ast::Mul(
+                    meta("2 * _", 1.0),
+                    Box::new(Ast::Num(meta("2", 1.0), 2)),
+                    Box::new(Ast::Hole),
+                ),
+            ],
+        );
+        let err = ast.try_ctor_tokens().unwrap_err();
+        assert_eq!(err.path().to_string(), "Ast::Sum.1[1].Mul.2.Hole");
+        assert_eq!(
+            err.to_string(),
+            "Attempted to call ctor_tokens() on skipped enum variant Ast::Hole (at `Ast::Sum.1[1].Mul.2.Hole`)"
+        );
+
+        let ast = Ast::Sum(
+            meta("1 + 2", 1.0),
+            vec![
+                Ast::Num(meta("1", 1.0), 1),
+                Ast::Num(meta("2", f64::NAN), 2),
+            ],
+        );
+        let err = ast.try_ctor_tokens().unwrap_err();
+        assert_eq!(err.path().to_string(), "Ast::Sum.1[1].Num.0.weight");
+
+        let err = meta("nan", f64::NAN).try_ctor_tokens().unwrap_err();
+        assert_eq!(err.path().to_string(), "Metadata.weight");
+
+        let map = HashMap::from([("nums", (1i32, vec![0.5f32, f32::INFINITY]))]);
+        let err = map.try_ctor_tokens().unwrap_err();
+        assert_eq!(err.path().to_string(), "[0].1.1[1]");
+    }
+
+    #[test]
+    fn test_context() {
+        #[derive(Quine)]
+        struct Node {
+            value: i32,
+            next: Option<Box<Node>>,
+        }
+
+        let node = Node {
+            value: 1i32,
+            next: Some(Box::new(Node {
+                value: 2i32,
+                next: None,
+            })),
+        };
+
+        let ctx = QuineContext::new()
+            .with_type_paths(TypePaths::Unqualified)
+            .with_std_paths(StdPaths::Std);
+        assert_ts_eq(
+            &node.ctor_tokens_with(&ctx),
+            &quote! {
+                Node {
+                    value: 1i32,
+                    next: ::core::option::Option::Some(::std::boxed::Box::new(Node {
+                        value: 2i32,
+                        next: ::core::option::Option::None
+                    }))
+                }
+            },
+        );
+
+        let ctx = QuineContext::new().with_sequences(SequenceStyle::Macro);
+        assert_ts_eq(
+            &vec![vec![1i32], vec![]].ctor_tokens_with(&ctx),
+            &quote! {::std::vec![::std::vec![1i32], ::std::vec![]]},
+        );
+
+        let ctx = QuineContext::new()
+            .with_std_paths(StdPaths::Unqualified)
+            .with_map_order(MapOrder::Sorted);
+        let map = HashMap::from([(3i32, "c"), (1i32, "a"), (2i32, "b")]);
+        assert_ts_eq(
+            &map.ctor_tokens_with(&ctx),
+            &quote! {HashMap::from([(1i32, "a"), (2i32, "b"), (3i32, "c")])},
+        );
+    }
+
+    #[test]
+    fn test_crate_paths() {
+        #[derive(Quine)]
+        #[polyquine(path(crate))]
+        struct Local(i32);
+
+        #[derive(Quine)]
+        #[polyquine(path(alias = renamed))]
+        enum Renamed {
+            A,
+        }
+
+        #[derive(Quine)]
+        #[polyquine(path($crate))]
+        struct Deferred {
+            value: Local,
+        }
+
+        assert_ts_eq(
+            &Local(1i32).ctor_tokens(),
+            &quote! {crate::quine::test::Local(1i32)},
+        );
+        assert_ts_eq(
+            &Renamed::A.ctor_tokens(),
+            &quote! {::renamed::quine::test::Renamed::A},
+        );
+
+        let deferred = Deferred { value: Local(2i32) };
+        let tokens = deferred.ctor_tokens();
+        assert_eq!(
+            tokens.to_string(),
+            "$ crate :: quine :: test :: Deferred { value : crate :: quine :: test :: Local (2i32) }"
+        );
+        assert_ts_eq(
+            &resolve_dollar_crate(tokens, &quote! {::polyquine}),
+            &quote! {::polyquine::quine::test::Deferred { value: crate::quine::test::Local(2i32) }},
+        );
+
+        // The context takes precedence over the types' attributes
+        let ctx = QuineContext::new().with_crate_path("polyquine", CratePath::Name);
+        assert_ts_eq(
+            &deferred.ctor_tokens_with(&ctx),
+            &quote! {polyquine::quine::test::Deferred { value: polyquine::quine::test::Local(2i32) }},
+        );
+
+        #[derive(Quine)]
+        #[allow(non_camel_case_types)]
+        struct r#async(u8);
+
+        assert_ts_eq(
+            &r#async(1u8).ctor_tokens(),
+            &quote! {polyquine::quine::test::r#async(1u8)},
+        );
+        assert_ts_eq(
+            &r#async(1u8)
+                .ctor_tokens_with(&QuineContext::new().with_type_paths(TypePaths::Unqualified)),
+            &quote! {r#async(1u8)},
+        );
+
+        // A package name is not a valid alias
+        assert!(matches!(
+            CratePath::alias("my-crate").unwrap_err().kind(),
+            QuineErrorKind::InvalidIdent(_)
+        ));
+        assert!(matches!(
+            CratePath::alias("my_crate"),
+            Ok(CratePath::Alias(_))
+        ));
+        let ctx =
+            QuineContext::new().with_crate_path("polyquine", CratePath::Alias("my-crate".into()));
+        assert!(matches!(
+            deferred.try_ctor_tokens_with(&ctx).unwrap_err().kind(),
+            QuineErrorKind::InvalidIdent(_)
+        ));
+    }
+
+    #[test]
+    fn test_no_std_paths() {
+        let ctx = QuineContext::new().with_std_paths(StdPaths::NoStd);
+        let value = (
+            Some(Box::new(String::from("boxed"))),
+            VecDeque::from([BTreeMap::from([(1i32, vec![true])])]),
+        );
+        assert_ts_eq(
+            &value.ctor_tokens_with(&ctx),
+            &quote! {(
+                ::core::option::Option::Some(::alloc::boxed::Box::new(
+                    ::alloc::string::String::from("boxed")
+                )),
+                ::alloc::collections::VecDeque::from([
+                    ::alloc::collections::BTreeMap::from([(1i32, ::alloc::vec::Vec::from([true]))])
+                ])
+            )},
+        );
+    }
+
+    #[test]
+    fn test_custom_arm() {
+        #[derive(Quine)]
+        enum TestEnum {
+            A,
+            #[polyquine(with = (val) => {
+                let new_val = val + 1;
+                quote! { TestEnum::B(#new_val) }
+            })]
+            B(i32),
+            #[polyquine(with = c_tokens)]
+            C {
+                value: i32,
+            },
+        }
+
+        fn c_tokens(value: &TestEnum) -> TokenStream {
+            match value {
+                TestEnum::C { value } => quote! { TestEnum::C { value: #value + 0 } },
+                _ => unreachable!(),
+            }
+        }
+
+        let a = TestEnum::A;
+        assert_ts_eq(
+            &a.ctor_tokens(),
+            &quote! {polyquine::quine::test::TestEnum::A},
+        );
+
+        let b = TestEnum::B(42i32);
+        assert_ts_eq(&b.ctor_tokens(), &quote! { TestEnum::B(43i32) });
+        let c = TestEnum::C { value: 1 };
+        assert_ts_eq(
+            &c.ctor_tokens(),
+            &quote! { TestEnum::C { value: 1i32 + 0 } },
+        );
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn test_deprecated_attributes() {
+        #[derive(Quine)]
+        #[path_prefix(some_module)]
+        #[allow(dead_code)]
+        struct Prefixed {
+            value: i32,
+        }
+
+        #[derive(Quine)]
+        enum TestEnum {
+            #[polyquine_skip]
+            A,
+            #[polyquine_with(arm = (val) => quote! { TestEnum::B(#val, 0) })]
+            B(i32),
+        }
+
+        assert_ts_eq(
+            &Prefixed { value: 1 }.ctor_tokens(),
+            &quote! {some_module::Prefixed { value: 1i32 }},
+        );
+        assert!(TestEnum::A.try_ctor_tokens().is_err());
+        assert_ts_eq(
+            &TestEnum::B(1).ctor_tokens(),
+            &quote! { TestEnum::B(1i32, 0) },
+        );
+    }
+
+    #[test]
+    fn test_generic_type_struct() {
+        type Int = i32;
+
+        #[derive(Quine)]
+        struct Inner<T> {
+            pub value: T,
+        }
+
+        #[derive(Quine)]
+        struct Outer<T = Int> {
+            inner: Inner<T>,
+        }
+
+        let a = Outer {
+            inner: Inner { value: 1i32 },
+        };
+        assert_ts_eq(
+            &a.ctor_tokens(),
+            &quote! {
+                polyquine::quine::test::Outer {
+                    inner: polyquine::quine::test::Inner {
+                        value: 1i32
+                    }
+                }
+            },
+        );
+    }
+
+    #[test]
+    fn test_inferred_bounds() {
+        // Not `Quine`
+        #[derive(Clone, Debug)]
+        struct Opaque;
+
+        trait Storage {
+            type Key;
+        }
+
+        struct Keys;
+
+        impl Storage for Keys {
+            type Key = u8;
+        }
+
+        // Bounded by `S::Key: Quine` only
+        #[derive(Quine)]
+        #[allow(dead_code)]
+        struct Entry<S: Storage, M, U>
+        where
+            M: Clone,
+        {
+            key: S::Key,
+            marker: PhantomData<M>,
+            #[polyquine(skip)]
+            unused: Option<U>,
+        }
+
+        #[derive(Quine)]
+        struct List<T> {
+            value: T,
+            next: Option<Box<List<T>>>,
+        }
+
+        fn tag_tokens<W: std::fmt::Debug>(tag: &W) -> TokenStream {
+            let tag = format!("{tag:?}");
+            quote! {#tag}
+        }
+
+        #[derive(Quine)]
+        struct Tagged<T, W> {
+            value: T,
+            #[polyquine(with = tag_tokens, bound = "W: std::fmt::Debug")]
+            tag: W,
+        }
+
+        #[derive(Quine)]
+        #[polyquine(bound = "T: Quine + Clone")]
+        struct Bounded<T> {
+            value: T,
+        }
+
+        let ctx = QuineContext::new()
+            .with_type_paths(TypePaths::Unqualified)
+            .with_std_paths(StdPaths::Unqualified);
+        let entry: Entry<Keys, Opaque, Opaque> = Entry {
+            key: 3,
+            marker: PhantomData,
+            unused: Some(Opaque),
+        };
+        assert_ts_eq(
+            &entry.ctor_tokens_with(&ctx),
+            &quote! {Entry { key: 3u8, marker: PhantomData, unused: ::core::default::Default::default() }},
+        );
+        let list = List {
+            value: 1u8,
+            next: Some(Box::new(List {
+                value: 2u8,
+                next: None,
+            })),
+        };
+        assert_ts_eq(
+            &list.ctor_tokens_with(&ctx),
+            &quote! {List { value: 1u8, next: Some(Box::new(List { value: 2u8, next: None })) }},
+        );
+        assert_ts_eq(
+            &Tagged {
+                value: 1u8,
+                tag: Opaque,
+            }
+            .ctor_tokens_with(&ctx),
+            &quote! {Tagged { value: 1u8, tag: "Opaque" }},
+        );
         assert_ts_eq(
-            &value.ctor_tokens(),
-            &quote! {some_module::Custom { value: 42i32 }},
+            &Bounded { value: 1u8 }.ctor_tokens_with(&ctx),
+            &quote! {Bounded { value: 1u8 }},
         );
     }
 
     #[test]
-    fn test_struct() {
+    fn test_type_args() {
         #[derive(Quine)]
-        struct TestStruct {
-            a: i32,
-            b: bool,
+        struct Pair<T> {
+            items: Vec<T>,
+            first: Option<T>,
         }
 
-        let test_struct = TestStruct { a: 1i32, b: true };
-        assert_ts_eq(
-            &test_struct.ctor_tokens(),
-            &quote! {polyquine::quine::test::TestStruct { a: 1i32, b: true }},
-        );
-    }
+        #[derive(Quine)]
+        #[allow(dead_code)]
+        enum Either<L, R> {
+            Left(L),
+            Right(R),
+        }
 
-    #[test]
-    fn test_tuple_struct() {
         #[derive(Quine)]
-        struct TestTupleStruct(i32, bool);
+        #[polyquine(ctor = Self::new)]
+        struct Marked<T, M> {
+            value: T,
+            marker: PhantomData<M>,
+        }
 
-        let test_tuple_struct = TestTupleStruct(1i32, true);
-        assert_ts_eq(
-            &test_tuple_struct.ctor_tokens(),
-            &quote! {polyquine::quine::test::TestTupleStruct(1i32, true)},
-        );
-    }
+        impl<T, M> Marked<T, M> {
+            fn new(value: T, marker: PhantomData<M>) -> Self {
+                Marked { value, marker }
+            }
+        }
 
-    #[test]
-    fn test_unit_struct() {
         #[derive(Quine)]
-        struct TestUnitStruct;
+        struct Buffer<const N: usize> {
+            bytes: [u8; N],
+        }
 
-        let test_unit_struct = TestUnitStruct {};
+        let ctx = QuineContext::new()
+            .with_type_paths(TypePaths::Unqualified)
+            .with_std_paths(StdPaths::Unqualified)
+            .with_type_args(TypeArgs::Turbofish);
+        let pair = Pair::<String> {
+            items: vec![],
+            first: None,
+        };
         assert_ts_eq(
-            &test_unit_struct.ctor_tokens(),
-            &quote! {polyquine::quine::test::TestUnitStruct {}},
+            &pair.ctor_tokens_with(&ctx),
+            &quote! {Pair::<String> { items: Vec::<String>::from([]), first: None::<String> }},
+        );
+        assert_ts_eq(
+            &Either::<u8, Option<u8>>::Left(1).ctor_tokens_with(&ctx),
+            &quote! {Either::<u8, Option<u8> >::Left(1u8)},
+        );
+        let marked = Marked::<Box<i32>, ()>::new(Box::new(1), PhantomData);
+        assert_ts_eq(
+            &marked.ctor_tokens_with(&ctx),
+            &quote! {Marked::<Box<i32>, _>::new(Box::<i32>::new(1i32), PhantomData)},
+        );
+        assert_ts_eq(
+            &Buffer { bytes: [1u8, 2u8] }.ctor_tokens_with(&ctx),
+            &quote! {Buffer::<{2usize}> { bytes: [1u8, 2u8] }},
+        );
+        let map: HashMap<(u8, char), [i32; 2]> = HashMap::new();
+        assert_ts_eq(
+            &map.ctor_tokens_with(&ctx),
+            &quote! {HashMap::<(u8, char,), [i32; 2]>::from([])},
+        );
+        let slice: Rc<[RcWeak<String>]> = Rc::from([]);
+        assert_ts_eq(
+            &slice.ctor_tokens_with(&ctx),
+            &quote! {Rc::<[Weak<String> ]>::from([])},
         );
-    }
 
-    #[test]
-    fn test_nested_struct() {
-        #[derive(Quine)]
-        struct Node {
-            value: i32,
-            next: Option<Box<Node>>,
-        }
+        // `vec!` cannot be given type arguments
+        let ctx = ctx.with_sequences(SequenceStyle::Macro);
+        assert_ts_eq(
+            &Vec::<u8>::new().ctor_tokens_with(&ctx),
+            &quote! {Vec::<u8>::from([])},
+        );
 
-        let node = Node {
-            value: 1i32,
-            next: Some(Box::new(Node {
-                value: 2i32,
-                next: None,
-            })),
-        };
         assert_ts_eq(
-            &node.ctor_tokens(),
-            &quote! {polyquine::quine::test::Node { value: 1i32, next: Some(Box::new(polyquine::quine::test::Node { value: 2i32, next: None })) }},
+            &pair.ctor_tokens_with(&QuineContext::new().with_type_paths(TypePaths::Unqualified)),
+            &quote! {Pair { items: ::std::vec::Vec::from([]), first: ::core::option::Option::None }},
         );
     }
 
     #[test]
-    fn test_enum() {
+    fn test_type_tokens() {
         #[derive(Quine)]
-        enum TestEnum {
-            A,
-            B(i32),
-            C { name: String },
+        struct Pair<T> {
+            first: T,
+            second: T,
         }
 
-        let a = TestEnum::A;
-        let b = TestEnum::B(1i32);
-        let c = TestEnum::C {
-            name: String::from("John"),
-        };
+        #[derive(Quine)]
+        struct Tagged<T> {
+            value: T,
+            tag: PhantomData<T>,
+        }
 
+        assert_ts_eq(&Vec::<i32>::type_tokens(), &quote! {::std::vec::Vec<i32>});
         assert_ts_eq(
-            &a.ctor_tokens(),
-            &quote! {polyquine::quine::test::TestEnum::A},
+            &<(u8, [char; 3], Box<Option<Ustr>>)>::type_tokens(),
+            &quote! {(u8, [char; 3], ::std::boxed::Box<::core::option::Option<::ustr::Ustr> >,)},
         );
+
+        let ctx = QuineContext::new()
+            .with_type_paths(TypePaths::Unqualified)
+            .with_std_paths(StdPaths::Unqualified);
         assert_ts_eq(
-            &b.ctor_tokens(),
-            &quote! {polyquine::quine::test::TestEnum::B(1i32)},
+            &HashMap::<String, Vec<&str>>::type_tokens_with(&ctx),
+            &quote! {HashMap<String, Vec<&str> >},
         );
         assert_ts_eq(
-            &c.ctor_tokens(),
-            &quote! {polyquine::quine::test::TestEnum::C { name: String::from("John") }},
+            &Pair::<Rc<str>>::type_tokens_with(&ctx),
+            &quote! {Pair<Rc<str> >},
         );
+        assert_ts_eq(&Tagged::<u8>::type_tokens_with(&ctx), &quote! {Tagged<u8>});
+
+        struct Opaque;
+
+        impl Quine for Opaque {
+            fn try_to_ctor_tokens_with(
+                &self,
+                _ctx: &QuineContext,
+                out: &mut TokenStream,
+            ) -> Result<(), QuineError> {
+                out.extend(quote! {Opaque});
+                Ok(())
+            }
+        }
+
+        let err = Option::<Opaque>::try_type_tokens().unwrap_err();
+        assert!(matches!(err.kind(), QuineErrorKind::UnnamedType(_)));
     }
 
     #[test]
-    fn test_ast() {
+    fn test_items() {
+        use crate::{quine_const, quine_static, try_quine_const_with, try_quine_static_with};
+
         #[derive(Quine)]
-        struct Metadata {
-            src: String,
+        struct Point {
+            x: i32,
+            y: Option<char>,
         }
 
         #[derive(Quine)]
-        enum Ast {
-            Num(Box<Metadata>, isize),
-            Mul(Box<Metadata>, Box<Ast>, Box<Ast>),
-            Sum(Box<Metadata>, Vec<Ast>),
+        #[polyquine(ctor = Self::new)]
+        struct Celsius(f64);
+
+        impl Celsius {
+            #[allow(dead_code)]
+            fn new(degrees: f64) -> Self {
+                Celsius(degrees)
+            }
         }
 
-        let ast = Ast::Sum(
-            Box::new(Metadata {
-                src: String::from("1 + (2 * 3)"),
-            }),
-            vec![
-                Ast::Num(
-                    Box::new(Metadata {
-                        src: String::from("1"),
-                    }),
-                    1,
-                ),
-                Ast::Mul(
-                    Box::new(Metadata {
-                        src: String::from("2 * 3"),
-                    }),
-                    Box::new(Ast::Num(
-                        Box::new(Metadata {
-                            src: String::from("2"),
-                        }),
-                        2,
-                    )),
-                    Box::new(Ast::Num(
-                        Box::new(Metadata {
-                            src: String::from("3"),
-                        }),
-                        3,
-                    )),
-                ),
-            ],
+        let point = Point { x: 1, y: None };
+        let ctx = QuineContext::new().with_type_paths(TypePaths::Unqualified);
+        assert_ts_eq(
+            &try_quine_static_with("ORIGIN", &point, &ctx).unwrap(),
+            &quote! {const ORIGIN: Point = Point { x: 1i32, y: ::core::option::Option::None };},
         );
+        assert_ts_eq(
+            &quine_const("PAIR", &(1u8, [true; 2])),
+            &quote! {const PAIR: (u8, [bool; 2],) = (1u8, [true, true]);},
+        );
+        // A `const`, so that values that are not `Sync` are fine
+        let cell = quine_static("CELL", &std::cell::RefCell::new(5u8)).to_string();
+        assert!(cell.starts_with("const CELL"), "{cell}");
 
+        // Values that allocate, and constructors that may not be `const`
         assert_ts_eq(
-            &ast.ctor_tokens(),
+            &quine_static("NAMES", &vec![String::from("a")]),
             &quote! {
-                polyquine::quine::test::Ast::Sum(
-                    Box::new(polyquine::quine::test::Metadata {
-                        src: String::from("1 + (2 * 3)")
-                    }),
-                    Vec::from([
-                        polyquine::quine::test::Ast::Num(
-                            Box::new(polyquine::quine::test::Metadata {
-                                src: String::from("1")
-                            }),
-                            1isize
-                        ),
-                        polyquine::quine::test::Ast::Mul(
-                            Box::new(polyquine::quine::test::Metadata {
-                                src: String::from("2 * 3")
-                            }),
-                            Box::new(polyquine::quine::test::Ast::Num(
-                                Box::new(polyquine::quine::test::Metadata {
-                                    src: String::from("2")
-                                }),
-                                2isize
-                            )),
-                            Box::new(polyquine::quine::test::Ast::Num(
-                                Box::new(polyquine::quine::test::Metadata {
-                                    src: String::from("3")
-                                }),
-                                3isize
-                            ))
-                        )
-                    ])
-                )
+                static NAMES: ::std::sync::LazyLock<::std::vec::Vec<::std::string::String> > =
+                    ::std::sync::LazyLock::new(|| ::std::vec::Vec::from([::std::string::String::from("a")]));
             },
         );
-    }
-
-    #[test]
-    fn test_ustr() {
-        let u1 = Ustr::from("the quick brown fox");
+        let err = try_quine_const_with("NAMES", &vec![String::from("a")], &ctx).unwrap_err();
+        assert!(matches!(err.kind(), QuineErrorKind::NotConst(_)));
+        let ctx = ctx.with_std_paths(StdPaths::Unqualified);
         assert_ts_eq(
-            &u1.ctor_tokens(),
-            &quote! {Ustr::from("the quick brown fox")},
+            &try_quine_static_with("BOILING", &Celsius(100.0), &ctx).unwrap(),
+            &quote! {static BOILING: LazyLock<Celsius> = LazyLock::new(|| Celsius::new(100f64));},
         );
-    }
-
-    #[test]
-    fn test_struct_with_generic() {
-        #[derive(Quine)]
-        struct Test<T: Display> {
-            value: T,
-        }
-
-        let good: Test<String> = Test {
-            value: String::from("Hello World"),
-        };
         assert_ts_eq(
-            &good.ctor_tokens(),
+            &quine_static("SOME", &Some(Box::new(1u8))),
             &quote! {
-                polyquine::quine::test::Test {
-                    value: String::from("Hello World")
-                }
+                static SOME: ::std::sync::LazyLock<::core::option::Option<::std::boxed::Box<u8> > > =
+                    ::std::sync::LazyLock::new(|| ::core::option::Option::Some(::std::boxed::Box::new(1u8)));
             },
         );
+
+        // Clones of deduplicated subtrees are not `const`
+        let ctx = ctx.with_dedup(Dedup::Subtrees);
+        let points = [[1i32, 2, 3, 4, 5, 6, 7, 8], [1, 2, 3, 4, 5, 6, 7, 8]];
+        let item = try_quine_static_with("POINTS", &points, &ctx)
+            .unwrap()
+            .to_string();
+        assert!(item.starts_with("static POINTS : LazyLock"), "{item}");
+        assert!(try_quine_const_with("POINTS", &points, &ctx).is_err());
     }
 
     #[test]
-    fn test_enum_with_generic() {
+    fn test_const_mode() {
+        use crate::try_quine_const_with;
+
         #[derive(Quine)]
-        enum TestEnum<T> {
-            A(T),
+        #[polyquine(const)]
+        struct Config {
+            name: String,
+            tags: Vec<String>,
+            parent: Option<Box<Config>>,
+            limits: HashMap<String, u32>,
+            #[allow(dead_code)]
+            #[polyquine(skip, default = 0)]
+            hits: u32,
         }
 
-        let a = TestEnum::A(String::from("Hello World"));
-        assert_ts_eq(
-            &a.ctor_tokens(),
-            &quote! {
-                polyquine::quine::test::TestEnum::A(String::from("Hello World"))
-            },
-        );
-    }
-
-    #[test]
-    #[should_panic(
-        expected = "Attempted to call ctor_tokens() on skipped enum variant TestEnum::B"
-    )]
-    fn test_skipped_variant() {
         #[derive(Quine)]
-        enum TestEnum {
-            A,
-            #[polyquine_skip]
-            B,
+        #[polyquine(const)]
+        struct Outer {
+            name: String,
+            inner: Inner,
         }
 
-        let a = TestEnum::A;
-        assert_ts_eq(
-            &a.ctor_tokens(),
-            &quote! {polyquine::quine::test::TestEnum::A},
-        );
-
-        let b = TestEnum::B;
-        let _ = b.ctor_tokens(); // This should panic
-    }
-
-    #[test]
-    fn test_custom_arm() {
         #[derive(Quine)]
-        enum TestEnum {
-            A,
-            #[polyquine_with(arm = (val) => {
-                let new_val = val + 1;
-                quote! { TestEnum::B(#new_val) }
-            })]
-            B(i32),
+        struct Inner {
+            s: String,
         }
 
-        let a = TestEnum::A;
-        assert_ts_eq(
-            &a.ctor_tokens(),
-            &quote! {polyquine::quine::test::TestEnum::A},
-        );
-
-        let b = TestEnum::B(42i32);
-        assert_ts_eq(&b.ctor_tokens(), &quote! { TestEnum::B(43i32) });
-    }
+        // The types of the generated code, which hold `&'static` references where the derived types allocate
+        #[allow(dead_code)]
+        mod mirror {
+            pub struct Config {
+                pub name: &'static str,
+                pub tags: &'static [&'static str],
+                pub parent: Option<&'static Config>,
+                pub limits: &'static [(&'static str, u32)],
+                pub hits: u32,
+            }
 
-    #[test]
-    fn test_generic_type_struct() {
-        type Int = i32;
+            pub struct Outer {
+                pub name: &'static str,
+                pub inner: Inner,
+            }
 
-        #[derive(Quine)]
-        struct Inner<T> {
-            pub value: T,
+            pub struct Inner {
+                pub s: String,
+            }
         }
 
-        #[derive(Quine)]
-        struct Outer<T = Int> {
-            inner: Inner<T>,
+        // The tokens of `$expr`, which must build a `$ty` of the mirror types
+        macro_rules! mirrored {
+            (const $ty:ty = $($expr:tt)*) => {{
+                {
+                    use mirror::*;
+                    const _: $ty = $($expr)*;
+                }
+                quote! {$($expr)*}
+            }};
+            (let $ty:ty = $($expr:tt)*) => {{
+                {
+                    use mirror::*;
+                    let _: $ty = $($expr)*;
+                }
+                quote! {$($expr)*}
+            }};
         }
 
-        let a = Outer {
-            inner: Inner { value: 1i32 },
+        let config = Config {
+            name: String::from("child"),
+            tags: vec![String::from("a"), String::from("b")],
+            parent: Some(Box::new(Config {
+                name: String::from("root"),
+                tags: Vec::new(),
+                parent: None,
+                limits: HashMap::from([(String::from("depth"), 3)]),
+                hits: 7,
+            })),
+            limits: HashMap::new(),
+            hits: 1,
         };
+        let ctx = QuineContext::new().with_type_paths(TypePaths::Unqualified);
+        let ctor = mirrored!(const Config = Config {
+            name: "child",
+            tags: &["a", "b"],
+            parent: ::core::option::Option::Some(&Config {
+                name: "root",
+                tags: &[],
+                parent: ::core::option::Option::None,
+                limits: &[("depth", 3u32)],
+                hits: 0
+            }),
+            limits: &[],
+            hits: 0
+        });
         assert_ts_eq(
-            &a.ctor_tokens(),
-            &quote! {
-                polyquine::quine::test::Outer {
-                    inner: polyquine::quine::test::Inner {
-                        value: 1i32
-                    }
-                }
+            &try_quine_const_with("CONFIG", &config, &ctx).unwrap(),
+            &quote! {const CONFIG: Config = #ctor;},
+        );
+
+        // Only the fields of a `const` type are in const form, not the values around it
+        let configs = vec![Config {
+            name: String::from("x"),
+            tags: Vec::new(),
+            parent: None,
+            limits: HashMap::new(),
+            hits: 0,
+        }];
+        assert_ts_eq(
+            &configs.ctor_tokens_with(&ctx),
+            &mirrored!(let Vec<Config> = ::std::vec::Vec::from([Config {
+                name: "x",
+                tags: &[],
+                parent: ::core::option::Option::None,
+                limits: &[],
+                hits: 0
+            }])),
+        );
+
+        // nor the fields of a derived type nested in it
+        let outer = Outer {
+            name: String::from("x"),
+            inner: Inner {
+                s: String::from("y"),
             },
+        };
+        assert_ts_eq(
+            &outer.ctor_tokens_with(&ctx),
+            &mirrored!(let Outer = Outer {
+                name: "x",
+                inner: Inner { s: ::std::string::String::from("y") }
+            }),
         );
+
+        #[derive(Quine)]
+        #[polyquine(const)]
+        struct Shared {
+            value: Rc<u8>,
+        }
+
+        let err = Shared { value: Rc::new(1) }.try_ctor_tokens().unwrap_err();
+        assert!(matches!(err.kind(), QuineErrorKind::NotConst(_)));
+        assert_eq!(err.path().to_string(), "Shared.value");
     }
 }
@@ -0,0 +1,85 @@
+use proc_macro2::{Delimiter, Group, Ident, Punct, Spacing, Span, TokenStream};
+use quote::TokenStreamExt;
+
+use crate::{QuineContext, QuineError, TypeArgs};
+
+// Building blocks for appending constructor tokens to a `TokenStream` in place.
+// Shared by the built-in `Quine` implementations, the `derive_*!` macros and the code generated by `#[derive(Quine)]`,
+// which write them through `Emitter::append`.
+
+/// Append the identifier `name`, which may be a raw identifier such as `r#type`.
+pub fn append_ident(out: &mut TokenStream, name: &str) {
+    match name.strip_prefix("r#") {
+        Some(name) => out.append(Ident::new_raw(name, Span::call_site())),
+        None => out.append(Ident::new(name, Span::call_site())),
+    }
+}
+
+/// Append `::name`.
+pub fn append_segment(out: &mut TokenStream, name: &str) {
+    out.append(Punct::new(':', Spacing::Joint));
+    out.append(Punct::new(':', Spacing::Alone));
+    append_ident(out, name);
+}
+
+/// Append `name:`, as in a struct expression.
+pub fn append_field(out: &mut TokenStream, name: &str) {
+    append_ident(out, name);
+    out.append(Punct::new(':', Spacing::Alone));
+}
+
+pub fn append_comma(out: &mut TokenStream) {
+    out.append(Punct::new(',', Spacing::Alone));
+}
+
+/// Append a group delimited by `delimiter`, whose contents are written by `f`.
+pub fn append_group(
+    out: &mut TokenStream,
+    delimiter: Delimiter,
+    f: impl FnOnce(&mut TokenStream) -> Result<(), QuineError>,
+) -> Result<(), QuineError> {
+    let mut inner = TokenStream::new();
+    f(&mut inner)?;
+    out.append(Group::new(delimiter, inner));
+    Ok(())
+}
+
+/// A function appending the tokens of a type, see [`Quine::try_to_type_tokens_with`](crate::Quine::try_to_type_tokens_with).
+pub type TypeTokensFn = fn(&QuineContext, &mut TokenStream) -> Result<(), QuineError>;
+
+/// Append `<A, B>`, with the tokens of each type written by `args`.
+pub fn append_type_args(
+    ctx: &QuineContext,
+    out: &mut TokenStream,
+    args: &[TypeTokensFn],
+) -> Result<(), QuineError> {
+    out.append(Punct::new('<', Spacing::Alone));
+    for (i, arg) in args.iter().enumerate() {
+        if i > 0 {
+            append_comma(out);
+        }
+        arg(ctx, out)?;
+    }
+    out.append(Punct::new('>', Spacing::Alone));
+    Ok(())
+}
+
+/// Append `::<A, B>` after the path of a generic constructor with [`TypeArgs::Turbofish`], otherwise nothing.
+pub fn append_turbofish(
+    ctx: &QuineContext,
+    out: &mut TokenStream,
+    args: &[TypeTokensFn],
+) -> Result<(), QuineError> {
+    if ctx.type_args() == TypeArgs::Turbofish {
+        out.append(Punct::new(':', Spacing::Joint));
+        out.append(Punct::new(':', Spacing::Alone));
+        append_type_args(ctx, out, args)?;
+    }
+    Ok(())
+}
+
+/// Append `_`, for a type argument that cannot be written out.
+pub fn append_infer(_ctx: &QuineContext, out: &mut TokenStream) -> Result<(), QuineError> {
+    append_ident(out, "_");
+    Ok(())
+}
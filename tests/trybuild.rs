@@ -1,3 +1,7 @@
+use std::cell::RefCell;
+use std::rc::{Rc, Weak};
+use std::sync::Arc;
+
 use quote::quote;
 
 #[test]
@@ -6,16 +10,106 @@ fn ui() {
     t.compile_fail("tests/ui/fail/*.rs");
     t.pass("tests/ui/succeed/*.rs");
 
-    use polyquine::Quine;
+    use polyquine::{
+        Cycles, Dedup, Nesting, Quine, QuineContext, QuineType, TypeArgs, quine_const, quine_static,
+    };
 
     mod private {
         use polyquine::Quine;
 
         #[derive(Quine)]
-        #[path_prefix(some_module)]
+        #[polyquine(prefix = some_module)]
         pub struct SomeStruct {
             pub value: String,
         }
+
+        #[derive(Quine)]
+        #[polyquine(path(crate))]
+        pub struct CrateRelative {
+            pub value: i32,
+        }
+
+        #[derive(Quine, Clone)]
+        #[polyquine(path(crate))]
+        pub struct Deep {
+            pub values: Vec<Option<Box<Deep>>>,
+        }
+
+        #[derive(Quine)]
+        #[polyquine(path(crate))]
+        pub struct TreeNode {
+            pub parent: std::rc::Weak<std::cell::RefCell<TreeNode>>,
+            pub children: Vec<std::rc::Rc<std::cell::RefCell<TreeNode>>>,
+        }
+
+        #[derive(Quine)]
+        #[polyquine(path(crate))]
+        #[polyquine(ctor = Self::new, args(start, end))]
+        #[allow(dead_code)]
+        pub struct Span {
+            start: usize,
+            end: usize,
+            len: usize,
+        }
+
+        impl Span {
+            pub fn new(start: usize, end: usize) -> Self {
+                Span {
+                    start,
+                    end,
+                    len: end - start,
+                }
+            }
+        }
+
+        #[derive(Quine)]
+        #[polyquine(path(crate))]
+        #[polyquine(hidden_ctor)]
+        pub struct Secret {
+            key: String,
+            level: u8,
+        }
+
+        impl Secret {
+            pub fn new(key: String, level: u8) -> Self {
+                Secret { key, level }
+            }
+        }
+
+        #[derive(Quine)]
+        #[polyquine(path(crate))]
+        pub struct Pair<T> {
+            pub items: Vec<T>,
+            pub first: Option<T>,
+        }
+
+        #[derive(Quine)]
+        #[polyquine(path(crate), ctor = Self::from_parts)]
+        #[allow(dead_code)]
+        pub struct Parts(pub i32, #[polyquine(expr = "\"b\"")] pub &'static str);
+
+        #[derive(Quine)]
+        #[polyquine(path(crate), ctor = crate::private::ordered)]
+        pub struct Ordered(pub u8, pub u8);
+
+        #[derive(Quine)]
+        #[polyquine(path(crate), via = Vec<i32>)]
+        pub struct Sorted(pub Vec<i32>);
+
+        impl From<&Sorted> for Vec<i32> {
+            fn from(sorted: &Sorted) -> Self {
+                sorted.0.clone()
+            }
+        }
+
+        #[derive(Quine)]
+        #[polyquine(path(crate))]
+        #[polyquine(const)]
+        pub struct Manifest {
+            pub name: String,
+            pub tags: Vec<String>,
+            pub parent: Option<Box<Manifest>>,
+        }
     }
 
     pub mod some_module {}
@@ -28,15 +122,96 @@ fn ui() {
             use polyquine::Quine;
 
             #[derive(Quine)]
-            #[path_prefix(crate::some_module)]
+            #[polyquine(prefix = crate::some_module)]
             pub struct SomeStruct {
                 pub value: String,
             }
+
+            pub struct CrateRelative {
+                pub value: i32,
+            }
+
+            #[derive(Clone)]
+            pub struct Deep {
+                pub values: Vec<Option<Box<Deep>>>,
+            }
+
+            pub struct TreeNode {
+                pub parent: std::rc::Weak<std::cell::RefCell<TreeNode>>,
+                pub children: Vec<std::rc::Rc<std::cell::RefCell<TreeNode>>>,
+            }
+
+            pub struct Span {
+                len: usize,
+            }
+
+            impl Span {
+                pub fn new(start: usize, end: usize) -> Self {
+                    Span { len: end - start }
+                }
+
+                pub fn len(&self) -> usize {
+                    self.len
+                }
+            }
+
+            #[derive(Quine)]
+            #[polyquine(hidden_ctor)]
+            pub struct Secret {
+                key: String,
+                level: u8,
+            }
+
+            impl Secret {
+                pub fn key(&self) -> &str {
+                    &self.key
+                }
+
+                pub fn level(&self) -> u8 {
+                    self.level
+                }
+            }
+
+            pub struct Pair<T> {
+                pub items: Vec<T>,
+                pub first: Option<T>,
+            }
+
+            pub struct Parts(pub i32, pub &'static str);
+
+            impl Parts {
+                pub fn from_parts(first: i32, second: &'static str) -> Self {
+                    Parts(first, second)
+                }
+            }
+
+            pub struct Ordered(pub u8, pub u8);
+
+            pub fn ordered(a: u8, b: u8) -> Ordered {
+                Ordered(a.min(b), a.max(b))
+            }
+
+            pub struct Sorted(pub Vec<i32>);
+
+            impl From<Vec<i32>> for Sorted {
+                fn from(mut items: Vec<i32>) -> Self {
+                    items.sort();
+                    Sorted(items)
+                }
+            }
+
+            pub struct Manifest {
+                pub name: &'static str,
+                pub tags: &'static [&'static str],
+                pub parent: Option<&'static Manifest>,
+            }
         }
         pub mod some_module {
             pub use crate::private::SomeStruct;
         }
-        fn main() {}
+        fn main() {
+            constructor::nope();
+        }
     );
     std::fs::write(&mod_file, mod_contents).unwrap();
 
@@ -46,12 +221,133 @@ fn ui() {
         value: "Hello".to_string(),
     }
     .ctor_tokens();
+    let crate_relative = private::CrateRelative { value: 42 }.ctor_tokens();
+    let mut deep = private::Deep { values: vec![] };
+    for _ in 0..10 {
+        deep = private::Deep {
+            values: vec![None, Some(Box::new(deep))],
+        };
+    }
+    let flat_ctx = QuineContext::new().with_nesting(Nesting::Flatten { max_depth: 3 });
+    let flat = deep.ctor_tokens_with(&flat_ctx);
+    let mut shared = private::Deep { values: vec![] };
+    for _ in 0..10 {
+        shared = private::Deep {
+            values: vec![Some(Box::new(shared.clone())), Some(Box::new(shared))],
+        };
+    }
+    let shared = shared.ctor_tokens_with(&flat_ctx.with_dedup(Dedup::Subtrees));
+    let leaf = Rc::new(String::from("leaf"));
+    let names = Arc::<[Arc<str>]>::from([Arc::from("name"), Arc::from("other")]);
+    let graph = (
+        vec![leaf.clone(), Rc::new(String::from("other")), leaf],
+        names.clone(),
+        names,
+    )
+        .ctor_tokens();
+    let root = Rc::new(RefCell::new(private::TreeNode {
+        parent: Weak::new(),
+        children: vec![],
+    }));
+    for _ in 0..2 {
+        let child = Rc::new(RefCell::new(private::TreeNode {
+            parent: Rc::downgrade(&root),
+            children: vec![],
+        }));
+        root.borrow_mut().children.push(child);
+    }
+    let tree = root.ctor_tokens_with(&QuineContext::new().with_cycles(Cycles::NewCyclic));
+    let span = private::Span::new(3, 7).ctor_tokens();
+    let secret = private::Secret::new(String::from("key"), 2).ctor_tokens();
+    let parts = private::Parts(1, "a").ctor_tokens();
+    let ordered = private::Ordered(2, 1).ctor_tokens();
+    let sorted = private::Sorted(vec![3, 1, 2]).ctor_tokens();
+    // Empty collections and `None` whose types are only known from the type arguments
+    let typed = (
+        private::Pair::<String> {
+            items: vec![],
+            first: None,
+        },
+        std::collections::HashMap::<u8, Vec<char>>::new(),
+        Rc::<[Weak<u8>]>::from([]),
+    )
+        .ctor_tokens_with(&QuineContext::new().with_type_args(TypeArgs::Turbofish));
+    // The same, inferred from the type of the `let`
+    let annotated_type = <(private::Pair<u8>, Option<[i32; 2]>)>::type_tokens();
+    let annotated = (
+        private::Pair::<u8> {
+            items: vec![],
+            first: None,
+        },
+        None::<[i32; 2]>,
+    )
+        .ctor_tokens();
+    let const_item = quine_const("LIMITS", &(3u8, Some('x'), [1i64, -1]));
+    let lazy_item = quine_static(
+        "NAMES",
+        &std::collections::BTreeMap::from([(1u8, String::from("one"))]),
+    );
+    // A `const`, which unlike a `static` need not be `Sync`
+    let cell_item = quine_static("CELL", &std::cell::RefCell::new(5u8));
+    // Emitted in const form, for a mirror type that holds `&'static` references
+    let manifest_item = quine_const(
+        "MANIFEST",
+        &private::Manifest {
+            name: String::from("app"),
+            tags: vec![String::from("cli")],
+            parent: Some(Box::new(private::Manifest {
+                name: String::from("base"),
+                tags: vec![],
+                parent: None,
+            })),
+        },
+    );
 
     let content = quote! {
         use super::*;
-        fn nope() {
+        #const_item
+        #lazy_item
+        #cell_item
+        #manifest_item
+        pub fn nope() {
             let s = #tokens;
             assert_eq!(s.value, "Hello".to_string());
+            let c = #crate_relative;
+            assert_eq!(c.value, 42);
+            let d = #flat;
+            assert_eq!(d.values.len(), 2);
+            let s = #shared;
+            assert_eq!(s.values.len(), 2);
+            let g = #graph;
+            assert!(std::rc::Rc::ptr_eq(&g.0[0], &g.0[2]));
+            assert!(!std::rc::Rc::ptr_eq(&g.0[0], &g.0[1]));
+            assert!(std::sync::Arc::ptr_eq(&g.1, &g.2));
+            let t = #tree;
+            for child in &t.borrow().children {
+                let parent = child.borrow().parent.upgrade().unwrap();
+                assert!(std::rc::Rc::ptr_eq(&parent, &t));
+            }
+            let span = #span;
+            assert_eq!(span.len(), 4);
+            let secret = #secret;
+            assert_eq!((secret.key(), secret.level()), ("key", 2));
+            let parts = #parts;
+            assert_eq!((parts.0, parts.1), (1, "b"));
+            let ordered = #ordered;
+            assert_eq!((ordered.0, ordered.1), (1, 2));
+            let sorted = #sorted;
+            assert_eq!(sorted.0, [1, 2, 3]);
+            let typed = #typed;
+            assert!(typed.0.items.is_empty() && typed.0.first.is_none());
+            assert!(typed.1.is_empty() && typed.2.is_empty());
+            let annotated: #annotated_type = #annotated;
+            assert!(annotated.0.items.is_empty() && annotated.1.is_none());
+            const _: u8 = LIMITS.0;
+            assert_eq!(LIMITS, (3, Some('x'), [1, -1]));
+            assert_eq!(NAMES[&1], "one");
+            assert_eq!(*CELL.borrow(), 5);
+            const _: &str = MANIFEST.parent.unwrap().name;
+            assert_eq!((MANIFEST.name, MANIFEST.tags), ("app", &["cli"][..]));
         }
     };
 
@@ -0,0 +1,21 @@
+use polyquine::Quine;
+
+#[derive(Quine)]
+enum Typo {
+    #[polyquine_with(amr = (value) => quote::quote! { Typo::A(#value) })]
+    A(i32),
+}
+
+#[derive(Quine)]
+enum MissingArrow {
+    #[polyquine_with(arm = (value) quote::quote! { MissingArrow::A(#value) })]
+    A(i32),
+}
+
+#[derive(Quine)]
+enum MissingBody {
+    #[polyquine(with = (value) =>)]
+    A(i32),
+}
+
+fn main() {}
@@ -0,0 +1,21 @@
+use polyquine::Quine;
+
+#[derive(Quine)]
+#[polyquine(ctor = Self::new, via = i32)]
+struct Container {
+    value: i32,
+}
+
+#[derive(Quine)]
+struct Field {
+    #[polyquine(skip, expr = "1")]
+    value: i32,
+}
+
+#[derive(Quine)]
+#[polyquine(args(value))]
+struct ArgsWithoutCtor {
+    value: i32,
+}
+
+fn main() {}
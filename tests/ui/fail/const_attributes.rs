@@ -0,0 +1,23 @@
+use polyquine::Quine;
+
+#[derive(Quine)]
+#[polyquine(ctor = Self::new, const)]
+struct ConstWithCtor {
+    value: i32,
+}
+
+#[derive(Quine)]
+#[polyquine(const)]
+struct SkipWithoutDefault {
+    name: String,
+    #[polyquine(skip)]
+    hits: u32,
+}
+
+#[derive(Quine)]
+struct ConstField {
+    #[polyquine(const)]
+    value: i32,
+}
+
+fn main() {}
@@ -0,0 +1,25 @@
+#![deny(deprecated)]
+
+use polyquine::Quine;
+
+#[derive(Quine)]
+#[path_prefix(some_module)]
+struct Prefixed {
+    value: i32,
+}
+
+#[derive(Quine)]
+#[polyquine_path(crate)]
+struct CrateRelative {
+    value: i32,
+}
+
+#[derive(Quine)]
+enum Legacy {
+    #[polyquine_skip]
+    A,
+    #[polyquine_with(arm = (value) => quote::quote! { Legacy::B(#value) })]
+    B(i32),
+}
+
+fn main() {}
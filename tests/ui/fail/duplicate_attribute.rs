@@ -0,0 +1,22 @@
+use polyquine::Quine;
+
+#[derive(Quine)]
+#[polyquine(prefix = a)]
+#[path_prefix(b)]
+struct Container {
+    value: i32,
+}
+
+#[derive(Quine)]
+struct Field {
+    #[polyquine(with = tokens, with = tokens)]
+    value: i32,
+}
+
+#[derive(Quine)]
+enum Variant {
+    #[polyquine(skip, skip)]
+    A,
+}
+
+fn main() {}
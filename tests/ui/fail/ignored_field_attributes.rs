@@ -0,0 +1,32 @@
+use polyquine::Quine;
+
+#[derive(Quine)]
+#[polyquine(with = emit_replaced)]
+struct Replaced {
+    #[polyquine(skip)]
+    value: i32,
+}
+
+#[derive(Quine)]
+#[polyquine(via = i32)]
+struct Converted {
+    #[polyquine(expr = "0")]
+    value: i32,
+}
+
+#[derive(Quine)]
+#[polyquine(with = emit_replaced)]
+struct Unknown {
+    #[polyquine(skp)]
+    value: i32,
+}
+
+#[derive(Quine)]
+#[polyquine(unsafe_active = int)]
+union Bits {
+    int: u32,
+    #[polyquine(skip)]
+    float: f32,
+}
+
+fn main() {}
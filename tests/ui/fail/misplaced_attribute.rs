@@ -0,0 +1,33 @@
+use polyquine::Quine;
+
+#[derive(Quine)]
+#[polyquine_skip]
+struct SkippedStruct {
+    value: i32,
+}
+
+#[derive(Quine)]
+struct SkippedField {
+    #[polyquine_skip]
+    value: i32,
+}
+
+#[derive(Quine)]
+enum PrefixedVariant {
+    #[polyquine(prefix = some_module)]
+    A,
+}
+
+#[derive(Quine)]
+#[polyquine(skip)]
+struct FieldKeyOnType {
+    value: i32,
+}
+
+#[derive(Quine)]
+struct TypeKeyOnField {
+    #[polyquine(via = i32)]
+    value: i32,
+}
+
+fn main() {}
@@ -0,0 +1,16 @@
+use polyquine::Quine;
+
+#[derive(Quine)]
+enum Test {
+    #[polyquine(skip, with = (value) => quote::quote! { Test::A(#value) })]
+    A(i32),
+}
+
+#[derive(Quine)]
+enum Legacy {
+    #[polyquine_skip]
+    #[polyquine_with(arm = (value) => quote::quote! { Legacy::A(#value) })]
+    A(i32),
+}
+
+fn main() {}
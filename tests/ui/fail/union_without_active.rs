@@ -0,0 +1,16 @@
+use polyquine::Quine;
+
+#[derive(Quine)]
+union Bits {
+    int: u32,
+    float: f32,
+}
+
+#[derive(Quine)]
+#[polyquine(active = int)]
+union Unchecked {
+    int: u32,
+    float: f32,
+}
+
+fn main() {}
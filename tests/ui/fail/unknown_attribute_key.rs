@@ -0,0 +1,15 @@
+use polyquine::Quine;
+
+#[derive(Quine)]
+#[polyquine(rename = "Other")]
+struct Container {
+    value: i32,
+}
+
+#[derive(Quine)]
+struct Field {
+    #[polyquine(skp)]
+    value: i32,
+}
+
+fn main() {}
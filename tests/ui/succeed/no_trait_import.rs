@@ -0,0 +1,13 @@
+// The derived impl must not rely on `Quine`, `quote`, `syn` or `proc_macro2` being in scope
+#[derive(polyquine::Quine)]
+enum Test<T> {
+    A(T),
+    B { value: Option<Box<Test<T>>> },
+}
+
+fn main() {
+    let test = Test::B {
+        value: Some(Box::new(Test::A(1i32))),
+    };
+    polyquine::Quine::ctor_tokens(&test);
+}
@@ -0,0 +1,16 @@
+mod reexport {
+    pub use polyquine as pq;
+}
+
+#[derive(reexport::pq::Quine)]
+#[polyquine(crate = crate::reexport::pq)]
+struct Test<T> {
+    value: T,
+}
+
+fn main() {
+    use reexport::pq::Quine;
+
+    let test = Test { value: 1i32 };
+    test.ctor_tokens();
+}